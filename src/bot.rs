@@ -1,20 +1,28 @@
-use std::{borrow::Cow, sync::Arc, time::Instant};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use egui::Color32;
 use hashbrown::HashMap;
 use librespot::{
     core::{session::Session, spotify_id::SpotifyId},
-    metadata::{image::ImageSize, Lyrics, Metadata, Track},
+    metadata::{Album, Metadata, Playlist},
 };
 use rspotify::{
-    model::{Country, Market, Page, SearchResult, SearchType},
+    model::{FullTrack, Market, Page, SearchResult, SearchType},
     prelude::BaseClient,
-    ClientCredsSpotify,
+    ClientCredsSpotify, ClientResult,
 };
 
-use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
-    oneshot,
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot, Semaphore,
+    },
+    task::JoinSet,
 };
 use twitch_message::messages::{
     types::{MsgId, UserId},
@@ -22,18 +30,110 @@ use twitch_message::messages::{
 };
 
 use crate::{
+    db,
     ext::JoinWith,
     history,
-    spotify_lyrics::SpotifyLyrics,
+    request::TrackInfo,
     twitch::{self, ChannelTarget},
+    util::{env_or, format_duration, format_remaining, pack_lines, select2, Either},
     Request,
 };
 
+mod limits;
+use limits::Limiter;
+pub use limits::Limits;
+
+mod link;
+use link::Link;
+
+mod roles;
+pub use roles::{Command, Role};
+
+mod search;
+use search::SearchQuery;
+pub use search::SearchRules;
+
+mod stats;
+use stats::TopKind;
+
+mod track_source;
+pub use track_source::TrackSource;
+
+mod vote;
+use vote::VoteSkip;
+pub use vote::VoteSkipRules;
+
+// how many request messages are remembered, moderators delete messages soon after they're sent
+const MAX_REQUESTED: usize = 100;
+
+pub struct Settings {
+    pub limits: Limits,
+    pub role_limits: BTreeMap<Role, Limits>,
+    pub vote_skip: VoteSkipRules,
+    // how many tracks from an album or playlist a moderator can add at once
+    pub max_list_tracks: usize,
+    // how many tracks of a list are looked up at the same time
+    pub lookup_concurrency: usize,
+    pub search: SearchRules,
+    // how long a search menu can be replied to
+    pub selection_ttl: Duration,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Self {
+            max_list_tracks: env_or("MAX_LIST_TRACKS", 25),
+            lookup_concurrency: env_or("LOOKUP_CONCURRENCY", 8usize).max(1),
+            search: SearchRules::from_env(),
+            selection_ttl: Duration::from_secs(env_or("SELECTION_TTL_SECS", 5 * 60)),
+            limits: Limits::from_env("LIMIT", Limits::default()),
+            role_limits: Limits::role_overrides_from_env(),
+            vote_skip: VoteSkipRules::from_env(),
+        }
+    }
+}
+
 pub enum SynthEvent<T> {
     Synthetic(T),
     Organic(T),
 }
 
+// this is how the bot asks the gui about its state
+pub enum Query {
+    Active(oneshot::Sender<Option<Request>>),
+    Queue(oneshot::Sender<QueueSnapshot>),
+    RemoveLatest {
+        user_id: UserId,
+        resp: oneshot::Sender<Removed>,
+    },
+    // these were requested by a message that a moderator deleted
+    RemoveRequests(Vec<uuid::Uuid>),
+    // this user was timed out or banned
+    RemoveUser(UserId),
+}
+
+// this is how the bot controls playback
+pub enum PlayerCommand {
+    Skip,
+    // this ends the active request without starting another one
+    Stop,
+    Pause,
+    Resume,
+    Volume(f64),
+}
+
+pub enum Removed {
+    Removed(Request),
+    Active(Request),
+    Nothing,
+}
+
+pub struct QueueSnapshot {
+    // how much of the active track is left
+    pub remaining: Duration,
+    pub queue: VecDeque<Request>,
+}
+
 struct SelectionItem {
     name: String,
     artist: String,
@@ -49,24 +149,39 @@ pub struct Selection {
 
 pub struct Bot {
     pub config: twitch::Config,
-    pub events: UnboundedReceiver<Privmsg<'static>>,
+    pub events: UnboundedReceiver<twitch::Event>,
     pub writer: twitch::Writer,
     pub produce: UnboundedSender<SynthEvent<Request>>,
-    pub requests: UnboundedSender<oneshot::Sender<Option<Request>>>,
+    pub requests: UnboundedSender<Query>,
+    pub commands: UnboundedSender<PlayerCommand>,
     pub session: Session,
+    pub tracks: TrackSource,
     pub spotify: ClientCredsSpotify,
     pub selection: HashMap<UserId, Selection>,
+    pub selection_ttl: Duration,
+    pub expired: HashMap<UserId, (MsgId, Instant)>,
+    pub requested: VecDeque<(MsgId, uuid::Uuid)>,
+    pub limiter: Limiter,
+    pub vote_skip: VoteSkip,
+    pub max_list_tracks: usize,
+    pub lookup_concurrency: usize,
+    pub search: SearchRules,
+    pub db: db::Connection,
 }
 
 impl Bot {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: twitch::Config,
-        events: UnboundedReceiver<Privmsg<'static>>,
+        settings: Settings,
+        events: UnboundedReceiver<twitch::Event>,
         writer: twitch::Writer,
         produce: UnboundedSender<SynthEvent<Request>>,
-        requests: UnboundedSender<oneshot::Sender<Option<Request>>>,
+        requests: UnboundedSender<Query>,
+        commands: UnboundedSender<PlayerCommand>,
         session: Session,
         spotify: ClientCredsSpotify,
+        db: db::Connection,
     ) -> Self {
         Self {
             config,
@@ -74,59 +189,158 @@ impl Bot {
             writer,
             produce,
             requests,
+            commands,
+            tracks: TrackSource::Spotify(session.clone()),
             session,
             spotify,
             selection: HashMap::new(),
+            selection_ttl: settings.selection_ttl,
+            expired: HashMap::new(),
+            requested: VecDeque::new(),
+            limiter: Limiter::new(settings.limits, settings.role_limits),
+            vote_skip: VoteSkip::new(settings.vote_skip),
+            max_list_tracks: settings.max_list_tracks,
+            lookup_concurrency: settings.lookup_concurrency,
+            search: settings.search,
+            db,
         }
     }
 
     pub async fn process(mut self) {
-        while let Some(msg) = self.events.recv().await {
+        let mut sweep = tokio::time::interval(
+            self.selection_ttl
+                .clamp(Duration::from_secs(1), Duration::from_secs(60)),
+        );
+
+        loop {
+            let next = {
+                let recv = std::pin::pin!(self.events.recv());
+                let tick = std::pin::pin!(sweep.tick());
+                select2(recv, tick).await
+            };
+
+            let msg = match next {
+                Either::Left(Some(twitch::Event::Privmsg(msg))) => msg,
+                Either::Left(Some(twitch::Event::ClearMsg { msg_id })) => {
+                    self.handle_clear_msg(&msg_id);
+                    continue;
+                }
+                Either::Left(Some(twitch::Event::ClearChat { user_id })) => {
+                    self.handle_clear_chat(user_id);
+                    continue;
+                }
+                Either::Left(None) => break,
+                Either::Right(..) => {
+                    self.sweep_selections();
+                    continue;
+                }
+            };
+
             let Some(user_id) = msg.user_id() else { continue };
             let msg_id = msg.msg_id().unwrap();
 
-            // TODO clear stale entries
+            self.vote_skip.seen(user_id);
 
             if self.handle_converstation(&msg, user_id, msg_id).await {
                 continue;
             }
 
-            if self.handle_send_title(&msg, msg_id).await {
-                continue;
-            }
+            let Some((command, args)) = Command::parse(&msg.data) else { continue };
 
-            let Some(req) = msg.data.strip_prefix("~req ") else { continue };
-            if let Some(track_id) = Self::try_parse(req, msg_id, &self.writer) {
-                self.handle_song_req(&msg, msg_id, track_id).await;
+            let role = Role::of(&msg);
+            if role < command.min_role() {
+                let data = format!(
+                    "you need to be a {min_role} to do that",
+                    min_role = command.min_role()
+                );
+                self.writer.reply(ChannelTarget::Main, msg_id, data);
                 continue;
             }
 
-            self.handle_search(&msg, req, user_id, msg_id).await;
+            match command {
+                Command::Song => self.handle_send_title(msg_id).await,
+                Command::Queue => self.handle_queue(msg_id).await,
+                Command::Remove => self.handle_remove(user_id, msg_id).await,
+                Command::Request if args.is_empty() => {}
+                Command::Request => match Link::parse(args) {
+                    Ok(Some(Link::Track(track_id))) => {
+                        self.handle_song_req(&msg, role, msg_id, track_id).await
+                    }
+                    Ok(Some(Link::Artist(..))) | Err(Link::UNSUPPORTED) => self.writer.reply(
+                        ChannelTarget::Main,
+                        msg_id,
+                        "only tracks, albums and playlists can be requested",
+                    ),
+                    Ok(Some(link)) => self.handle_list_req(&msg, role, msg_id, link).await,
+                    Ok(None) => self.handle_search(&msg, args, user_id, msg_id).await,
+                    Err(err) => self.writer.reply(ChannelTarget::Main, msg_id, err),
+                },
+                Command::VoteSkip => self.handle_vote_skip(user_id, msg_id).await,
+                Command::Skip => self.handle_skip(msg_id).await,
+                Command::Pause => {
+                    let _ = self.commands.send(PlayerCommand::Pause);
+                    self.writer.reply(ChannelTarget::Main, msg_id, "paused");
+                }
+                Command::Resume => {
+                    let _ = self.commands.send(PlayerCommand::Resume);
+                    self.writer.reply(ChannelTarget::Main, msg_id, "resumed");
+                }
+                Command::Volume => self.handle_volume(args, msg_id),
+                Command::Ban => self.handle_block(&msg, args, msg_id, true),
+                Command::Unban => self.handle_block(&msg, args, msg_id, false),
+                Command::Top => self.handle_top(args, msg_id),
+                Command::Stats => self.handle_stats(msg_id),
+                Command::MyStats => self.handle_my_stats(&msg, msg_id),
+            }
         }
     }
 
-    // TODO make this parser more dynamic
-    // TODO show play stats
-    // TODO allow for ~prev
-    // TODO allow for aliases
-    async fn handle_send_title(&mut self, msg: &Privmsg<'_>, msg_id: &MsgIdRef) -> bool {
-        if msg.data != "~song" {
-            return false;
+    fn handle_clear_msg(&mut self, msg_id: &MsgId) {
+        let ids = self
+            .requested
+            .iter()
+            .filter(|(id, _)| id.as_str() == msg_id.as_str())
+            .map(|(_, id)| *id)
+            .collect::<Vec<_>>();
+
+        if !ids.is_empty() {
+            log::info!("removing requests from deleted message: {msg_id}");
+            let _ = self.requests.send(Query::RemoveRequests(ids));
         }
+    }
 
-        let (tx, rx) = oneshot::channel();
-        let _ = self.requests.send(tx);
-        let resp = rx.await.expect("gui is running");
+    fn handle_clear_chat(&mut self, user_id: UserId) {
+        log::info!("removing requests from timed out user: {user_id}");
+        self.selection.remove(&user_id);
+        let _ = self.requests.send(Query::RemoveUser(user_id));
+    }
 
-        let Some(resp) = resp else {
+    // the message is remembered so the request can be removed if a moderator deletes it
+    fn produce_request(&mut self, msg_id: &MsgIdRef, req: Request) {
+        if self.requested.len() == MAX_REQUESTED {
+            self.requested.pop_front();
+        }
+        self.requested.push_back((msg_id.to_owned(), req.id));
+        let _ = self.produce.send(SynthEvent::Organic(req));
+    }
+
+    // TODO allow for ~prev
+    // TODO allow for aliases
+    async fn handle_send_title(&mut self, msg_id: &MsgIdRef) {
+        let Some(resp) = self.active().await else {
             self.writer.reply(ChannelTarget::Main, msg_id, "nothing is playing");
-            return true
+            return
+        };
+
+        let plays = match self.db.play_count(resp.track.id) {
+            Ok(0 | 1) | Err(..) => String::new(),
+            Ok(plays) => format!(", played {plays} times"),
         };
 
         self.writer.say(
             ChannelTarget::Main,
             format!(
-                "{name} by {artist} (requested by {user}) \
+                "{name} by {artist} (requested by {user}{plays}) \
                  @ https://open.spotify.com/track/{id}",
                 name = resp.track.name,
                 artist = resp.track.artists.iter().map(|c| &c.name).join(", "),
@@ -134,8 +348,336 @@ impl Bot {
                 id = resp.track.id.to_base62().unwrap(),
             ),
         );
+    }
+
+    fn handle_top(&mut self, args: &str, msg_id: &MsgIdRef) {
+        // how many entries are listed
+        const TOP: usize = 5;
+
+        let (kind, window) = match stats::parse_top(args) {
+            Ok(query) => query,
+            Err(usage) => {
+                self.writer.reply(ChannelTarget::Main, msg_id, usage);
+                return;
+            }
+        };
+
+        let ranked = match kind {
+            TopKind::Tracks => self.db.top_tracks(&window.range(), TOP),
+            TopKind::Artists => self.db.top_artists(&window.range(), TOP),
+        };
+
+        let ranked = match ranked {
+            Ok(ranked) if ranked.is_empty() => {
+                let data = format!("nothing was requested {}", window.describe());
+                self.writer.reply(ChannelTarget::Main, msg_id, data);
+                return;
+            }
+            Ok(ranked) => ranked,
+            Err(err) => {
+                log::error!("cannot look up the top {}: {err}", kind.as_str());
+                self.writer
+                    .reply(ChannelTarget::Main, msg_id, "cannot look that up :(");
+                return;
+            }
+        };
+
+        let header = format!("top {} {}:", kind.as_str(), window.describe());
+        let entries = std::iter::once(header).chain(ranked.iter().enumerate().map(
+            |(index, db::Ranked { name, count })| {
+                format!("#{index} {name} ({count})", index = index + 1)
+            },
+        ));
+
+        for line in pack_lines(entries, " | ", twitch::MAX_MESSAGE_LENGTH) {
+            self.writer.reply(ChannelTarget::Main, msg_id, line);
+        }
+    }
+
+    fn handle_stats(&mut self, msg_id: &MsgIdRef) {
+        let db::ChannelStats {
+            played,
+            listened,
+            requests,
+            requesters,
+        } = match self.db.channel_stats() {
+            Ok(stats) => stats,
+            Err(err) => {
+                log::error!("cannot look up the channel stats: {err}");
+                self.writer
+                    .reply(ChannelTarget::Main, msg_id, "cannot look that up :(");
+                return;
+            }
+        };
+
+        let data = format!(
+            "{played} songs played ({listened} listened), \
+            {requests} requests from {requesters} chatters",
+            listened = format_remaining(listened),
+        );
+        self.writer.reply(ChannelTarget::Main, msg_id, data);
+    }
+
+    fn handle_my_stats(&mut self, msg: &Privmsg<'_>, msg_id: &MsgIdRef) {
+        let Some(user_id) = msg.user_id() else { return };
+
+        let db::UserStats {
+            requests,
+            favourite_artist,
+        } = match self.db.user_stats(user_id.as_str()) {
+            Ok(stats) => stats,
+            Err(err) => {
+                log::error!("cannot look up the stats for {}: {err}", user_id.as_str());
+                self.writer
+                    .reply(ChannelTarget::Main, msg_id, "cannot look that up :(");
+                return;
+            }
+        };
+
+        let data = match favourite_artist {
+            _ if requests == 0 => String::from("you haven't requested anything yet"),
+            Some(db::Ranked { name, count }) => {
+                format!("you've requested {requests} songs, mostly {name} ({count})")
+            }
+            None => format!("you've requested {requests} songs"),
+        };
+        self.writer.reply(ChannelTarget::Main, msg_id, data);
+    }
+
+    async fn handle_skip(&mut self, msg_id: &MsgIdRef) {
+        let Some(active) = self.active().await else {
+            self.writer.reply(ChannelTarget::Main, msg_id, "nothing is playing");
+            return
+        };
+
+        self.skip(&active, msg_id).await;
+    }
+
+    async fn handle_vote_skip(&mut self, user_id: &UserIdRef, msg_id: &MsgIdRef) {
+        let Some(active) = self.active().await else {
+            self.writer.reply(ChannelTarget::Main, msg_id, "nothing is playing");
+            return
+        };
+
+        // the requester doesn't have to wait for anyone else
+        if active.user.id.as_str() == user_id.as_str() {
+            self.skip(&active, msg_id).await;
+            return;
+        }
+
+        let tally = self.vote_skip.vote(active.id, user_id);
+        if tally.passed() {
+            self.skip(&active, msg_id).await;
+            return;
+        }
+
+        let data = format!(
+            "{votes}/{required} votes to skip {name} by {artist}",
+            votes = tally.votes,
+            required = tally.required,
+            name = active.track.name,
+            artist = active.track.artists.iter().map(|c| &c.name).join(", "),
+        );
+        self.writer.reply(ChannelTarget::Main, msg_id, data);
+    }
+
+    // with nothing to skip to, playback is stopped instead
+    async fn skip(&mut self, active: &Request, msg_id: &MsgIdRef) {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.requests.send(Query::Queue(tx));
+        let QueueSnapshot { queue, .. } = rx.await.expect("gui is running");
+
+        let command = if queue.is_empty() {
+            PlayerCommand::Stop
+        } else {
+            PlayerCommand::Skip
+        };
+        let _ = self.commands.send(command);
+        self.vote_skip.reset();
+
+        let data = format!(
+            "skipped {name} by {artist}",
+            name = active.track.name,
+            artist = active.track.artists.iter().map(|c| &c.name).join(", "),
+        );
+        self.writer.reply(ChannelTarget::Main, msg_id, data);
+    }
+
+    fn handle_volume(&mut self, args: &str, msg_id: &MsgIdRef) {
+        let Some(volume) = args
+            .trim_end_matches('%')
+            .parse::<u8>()
+            .ok()
+            .filter(|&volume| volume <= 100)
+        else {
+            self.writer.reply(
+                ChannelTarget::Main,
+                msg_id,
+                "the volume must be between 0 and 100",
+            );
+            return;
+        };
+
+        let _ = self
+            .commands
+            .send(PlayerCommand::Volume(f64::from(volume) / 100.0));
+
+        self.writer.reply(
+            ChannelTarget::Main,
+            msg_id,
+            format!("volume set to {volume}%"),
+        );
+    }
+
+    fn handle_block(&mut self, msg: &Privmsg<'_>, args: &str, msg_id: &MsgIdRef, block: bool) {
+        const USAGE: &str = "usage: song <id or url>, artist <id or url> or title <keyword>";
+
+        let Some((kind, value)) = args
+            .split_once(' ')
+            .map(|(kind, value)| (kind, value.trim()))
+            .filter(|(_, value)| !value.is_empty())
+        else {
+            self.writer.reply(ChannelTarget::Main, msg_id, USAGE);
+            return;
+        };
+
+        let (kind, value) = match kind {
+            "title" => (db::BlockKind::Keyword, value.to_string()),
+            "song" | "track" | "artist" => match Self::parse_block_target(kind, value) {
+                Ok(target) => target,
+                Err(err) => {
+                    self.writer.reply(ChannelTarget::Main, msg_id, err);
+                    return;
+                }
+            },
+            _ => {
+                self.writer.reply(ChannelTarget::Main, msg_id, USAGE);
+                return;
+            }
+        };
+
+        let data = match (block, kind) {
+            (true, kind) if self.db.block(kind, &value, &msg.sender) => {
+                format!("blocked that {kind}", kind = kind.as_str())
+            }
+            (true, kind) => format!("that {kind} is already blocked", kind = kind.as_str()),
+            (false, kind) if self.db.unblock(kind, &value) => {
+                format!("unblocked that {kind}", kind = kind.as_str())
+            }
+            (false, kind) => format!("that {kind} isn't blocked", kind = kind.as_str()),
+        };
+        self.writer.reply(ChannelTarget::Main, msg_id, data);
+    }
+
+    fn parse_block_target(
+        kind: &str,
+        value: &str,
+    ) -> Result<(db::BlockKind, String), &'static str> {
+        let kind = if kind == "artist" { "artist" } else { "track" };
+        const NOT_SONG_OR_ARTIST: &str = "that isn't a song or an artist";
+        let link = match Link::parse(value) {
+            Ok(Some(link)) => link,
+            Ok(None) => Link::from_parts(kind, value)?,
+            Err(Link::UNSUPPORTED) => return Err(NOT_SONG_OR_ARTIST),
+            Err(err) => return Err(err),
+        };
+
+        let (kind, id) = match link {
+            Link::Track(id) if kind == "track" => (db::BlockKind::Track, id),
+            Link::Artist(id) if kind == "artist" => (db::BlockKind::Artist, id),
+            _ => return Err(NOT_SONG_OR_ARTIST),
+        };
+
+        id.to_uri()
+            .map(|uri| (kind, uri))
+            .map_err(|_| "invalid spotify URN")
+    }
+
+    // this explains why a request cannot be made, if its blocked
+    fn check_blocked(&self, req: &Request) -> Option<&'static str> {
+        let blocked = self.db.find_blocked(
+            req.track.id,
+            req.track.artists.iter().map(|c| c.id),
+            &req.track.name,
+        )?;
+
+        Some(match blocked.kind {
+            db::BlockKind::Track => "that song is blocked",
+            db::BlockKind::Artist => "that artist is blocked",
+            db::BlockKind::Keyword => "that song cannot be requested",
+        })
+    }
+
+    async fn active(&mut self) -> Option<Request> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.requests.send(Query::Active(tx));
+        rx.await.expect("gui is running")
+    }
+
+    async fn handle_queue(&mut self, msg_id: &MsgIdRef) {
+        // how many upcoming requests are listed
+        const PREVIEW: usize = 5;
+
+        let (tx, rx) = oneshot::channel();
+        let _ = self.requests.send(Query::Queue(tx));
+        let QueueSnapshot { remaining, queue } = rx.await.expect("gui is running");
+
+        if queue.is_empty() {
+            self.writer
+                .reply(ChannelTarget::Main, msg_id, "nothing is queued");
+            return;
+        }
+
+        let mut eta = remaining;
+        let mut entries = queue
+            .iter()
+            .take(PREVIEW)
+            .enumerate()
+            .map(|(index, req)| {
+                let entry = format!(
+                    "#{index} {name} by {artist} (requested by {user}) in {eta}",
+                    index = index + 1,
+                    name = req.track.name,
+                    artist = req.track.artists.iter().map(|c| &c.name).join(", "),
+                    user = req.user.name,
+                    eta = format_duration(eta.as_millis() as _),
+                );
+                eta += Duration::from_millis(req.track.duration as _);
+                entry
+            })
+            .collect::<Vec<_>>();
+
+        if queue.len() > PREVIEW {
+            entries.push(format!("and {} more", queue.len() - PREVIEW));
+        }
+
+        for line in pack_lines(entries, " | ", twitch::MAX_MESSAGE_LENGTH) {
+            self.writer.reply(ChannelTarget::Main, msg_id, line);
+        }
+    }
+
+    async fn handle_remove(&mut self, user_id: &UserIdRef, msg_id: &MsgIdRef) {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.requests.send(Query::RemoveLatest {
+            user_id: user_id.to_owned(),
+            resp: tx,
+        });
+
+        let data = match rx.await.expect("gui is running") {
+            Removed::Removed(req) => format!(
+                "removed {name} by {artist}",
+                name = req.track.name,
+                artist = req.track.artists.iter().map(|c| &c.name).join(", "),
+            ),
+            Removed::Active(req) => format!(
+                "{name} by {artist} is already playing",
+                name = req.track.name,
+                artist = req.track.artists.iter().map(|c| &c.name).join(", "),
+            ),
+            Removed::Nothing => "you don't have anything queued".to_string(),
+        };
 
-        true
+        self.writer.reply(ChannelTarget::Main, msg_id, data);
     }
 
     async fn handle_converstation(
@@ -150,6 +692,17 @@ impl Bot {
 
         let Some(parent_msg_id) = msg.reply_parent_msg_id() else { return false };
 
+        self.sweep_selections();
+        if matches!(self.expired.get(user_id), Some((id, _)) if *id == parent_msg_id) {
+            self.writer.reply(
+                ChannelTarget::Spam,
+                parent_msg_id,
+                "that search has expired, search again with ~req",
+            );
+            self.expired.remove(user_id);
+            return true;
+        }
+
         let mut remove = false;
         if let Some(selection) = self
             .selection
@@ -240,6 +793,17 @@ impl Bot {
 
             let spotify_id = SpotifyId::from_uri(&item.id).expect("valid id");
 
+            let queued = Self::queued_by(&self.requests, user_id).await;
+            let last_requested = self.db.last_requested(spotify_id);
+            if let Err(rejection) =
+                self.limiter
+                    .check(user_id, Role::of(msg), queued, last_requested)
+            {
+                self.writer
+                    .reply(ChannelTarget::Spam, parent_msg_id, rejection);
+                return true;
+            }
+
             let item = history::HistoryItem {
                 id: uuid::Uuid::new_v4(),
                 added_on: time::OffsetDateTime::now_utc(),
@@ -255,12 +819,19 @@ impl Bot {
                 }),
             };
 
-            let Some(req) = item.lookup(&self.session).await else {
+            let Some(info) = self.tracks.fetch(item.spotify_id).await else {
                 let data = "cannot look up that item :(";
                 self.writer.say(ChannelTarget::Main, data);
                 self.writer.reply(ChannelTarget::Spam, parent_msg_id, data);
                 return false;
             };
+            let req = item.with_info(info);
+
+            if let Some(reason) = self.check_blocked(&req) {
+                self.writer
+                    .reply(ChannelTarget::Spam, parent_msg_id, reason);
+                return true;
+            }
 
             let data = format!(
                 "added {name} by {artist} \
@@ -272,7 +843,9 @@ impl Bot {
             self.writer.say(ChannelTarget::Main, &data);
             self.writer.reply(ChannelTarget::Spam, parent_msg_id, data);
 
-            let _ = self.produce.send(SynthEvent::Organic(req));
+            self.limiter.record(user_id);
+            // the ~req message is the one a moderator would delete, not the reply to the menu
+            self.produce_request(parent_msg_id, req);
             remove = true
         }
 
@@ -283,80 +856,191 @@ impl Bot {
         false
     }
 
-    async fn handle_song_req(&mut self, msg: &Privmsg<'_>, msg_id: &MsgIdRef, track_id: SpotifyId) {
-        let Ok(track) = Track::get(&self.session, &track_id).await.map(Arc::new) else {
+    // old menus are kept around for a while so replies to them can be explained
+    fn sweep_selections(&mut self) {
+        let ttl = self.selection_ttl;
+        self.expired
+            .retain(|_, (_, expired)| expired.elapsed() < ttl);
+
+        let expired = &mut self.expired;
+        self.selection.retain(|user_id, selection| {
+            if selection.created.elapsed() < ttl {
+                return true;
+            }
+            expired.insert(user_id.clone(), (selection.msg_id.clone(), Instant::now()));
+            false
+        });
+    }
+
+    async fn handle_song_req(
+        &mut self,
+        msg: &Privmsg<'_>,
+        role: Role,
+        msg_id: &MsgIdRef,
+        track_id: SpotifyId,
+    ) {
+        let user_id = msg.user_id().unwrap();
+
+        let queued = Self::queued_by(&self.requests, user_id).await;
+        let last_requested = self.db.last_requested(track_id);
+        if let Err(rejection) = self.limiter.check(user_id, role, queued, last_requested) {
+            self.writer.reply(ChannelTarget::Main, msg_id, rejection);
+            return;
+        }
+
+        let Some(req) = Self::lookup_request(&self.tracks, msg, track_id).await else {
+            self.writer
+                .reply(ChannelTarget::Main, msg_id, "cannot look up that track :(");
             return
         };
 
-        let image_id = track
-            .album
-            .covers
-            .iter()
-            .find_map(|c| (c.size == ImageSize::DEFAULT).then_some(c.id));
+        if let Some(reason) = self.check_blocked(&req) {
+            self.writer.reply(ChannelTarget::Main, msg_id, reason);
+            return;
+        }
 
-        let lyrics = Lyrics::get(&self.session, &track_id)
-            .await
-            .ok()
-            .map(|lyrics| SpotifyLyrics::fix_up(lyrics, track.duration as _))
-            .unwrap_or_default();
+        let data = format!(
+            "added {name} by {artist} \
+            @ https://open.spotify.com/track/{id}",
+            name = req.track.name,
+            artist = req.track.artists.iter().map(|c| &c.name).join(", "),
+            id = req.track.id.to_base62().unwrap(),
+        );
+
+        self.writer.say(ChannelTarget::Main, &data);
+        self.writer.reply(ChannelTarget::Spam, msg_id, &data);
+
+        self.limiter.record(user_id);
+        self.produce_request(msg_id, req);
+    }
+
+    // moderators get the whole list, everyone else gets a random track from it
+    async fn handle_list_req(
+        &mut self,
+        msg: &Privmsg<'_>,
+        role: Role,
+        msg_id: &MsgIdRef,
+        link: Link,
+    ) {
+        let tracks = match link {
+            Link::Track(..) | Link::Artist(..) => return,
+            Link::Album(id) => Album::get(&self.session, &id)
+                .await
+                .map(|album| album.tracks().copied().collect::<Vec<_>>()),
+            Link::Playlist(id) => Playlist::get(&self.session, &id)
+                .await
+                .map(|playlist| playlist.tracks().copied().collect::<Vec<_>>()),
+        };
+
+        let tracks = match tracks {
+            Ok(tracks) if !tracks.is_empty() => tracks,
+            Ok(..) => {
+                let data = format!("that {kind} is empty", kind = link.kind());
+                self.writer.reply(ChannelTarget::Main, msg_id, data);
+                return;
+            }
+            Err(err) => {
+                log::error!("cannot lookup {kind}: {err}", kind = link.kind());
+                let data = format!("cannot look up that {kind} :(", kind = link.kind());
+                self.writer.reply(ChannelTarget::Main, msg_id, data);
+                return;
+            }
+        };
+
+        if role < Role::Moderator {
+            let track_id = tracks[fastrand::usize(..tracks.len())];
+            self.handle_song_req(msg, role, msg_id, track_id).await;
+            return;
+        }
+
+        let Some(user) = Self::requester(msg) else { return };
+
+        // these are looked up at the same time, so chat isn't held up for the whole list
+        let permits = Arc::new(Semaphore::new(self.lookup_concurrency));
+        let mut set = JoinSet::new();
+        for (index, track_id) in tracks.into_iter().take(self.max_list_tracks).enumerate() {
+            let (source, user) = (self.tracks.clone(), user.clone());
+            let permits = Arc::clone(&permits);
+            set.spawn(async move {
+                let _permit = permits
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                (index, Self::lookup_track(&source, user, track_id).await)
+            });
+        }
+
+        // the list order is kept, no matter which lookup finished first
+        let mut found = BTreeMap::new();
+        while let Some(result) = set.join_next().await {
+            if let Ok((index, Some(req))) = result {
+                found.insert(index, req);
+            }
+        }
+
+        let mut added = 0;
+        for req in found.into_values() {
+            if self.check_blocked(&req).is_some() {
+                continue;
+            }
+            self.produce_request(msg_id, req);
+            added += 1;
+        }
+
+        let data = format!("added {added} tracks from that {kind}", kind = link.kind());
+        self.writer.say(ChannelTarget::Main, &data);
+        self.writer.reply(ChannelTarget::Spam, msg_id, data);
+    }
+
+    async fn lookup_request(
+        source: &TrackSource,
+        msg: &Privmsg<'_>,
+        track_id: SpotifyId,
+    ) -> Option<Request> {
+        Self::lookup_track(source, Self::requester(msg)?, track_id).await
+    }
 
-        let user = twitch::User {
-            id: msg.user_id().unwrap().to_owned(),
+    fn requester(msg: &Privmsg<'_>) -> Option<twitch::User> {
+        Some(twitch::User {
+            id: msg.user_id()?.to_owned(),
             color: msg
                 .color()
                 .map_or(Color32::GRAY, |twitch_message::Color(r, g, b)| {
                     Color32::from_rgb(r, g, b)
                 }),
             name: msg.sender.clone().into_owned(),
-        };
-
-        let data = format!(
-            "added {name} by {artist}
-            @ https://open.spotify.com/track/{id}",
-            name = track.name,
-            artist = track.artists.iter().map(|c| &c.name).join(", "),
-            id = track.id.to_base62().unwrap(),
-        );
+        })
+    }
 
-        self.writer.say(ChannelTarget::Main, &data);
-        self.writer.reply(ChannelTarget::Spam, msg_id, &data);
+    async fn lookup_track(
+        source: &TrackSource,
+        user: twitch::User,
+        track_id: SpotifyId,
+    ) -> Option<Request> {
+        let TrackInfo {
+            track,
+            image_id,
+            lyrics,
+        } = source.fetch(track_id).await?;
 
-        let _ = self.produce.send(SynthEvent::Organic(Request {
+        Some(Request {
             id: uuid::Uuid::new_v4(),
             added_on: time::OffsetDateTime::now_utc(),
             track,
             image_id,
             user,
             lyrics,
-        }));
+        })
     }
 
-    fn try_parse(input: &str, msg_id: &MsgIdRef, writer: &twitch::Writer) -> Option<SpotifyId> {
-        macro_rules! nope {
-            ($msg:expr) => {{
-                writer.reply(ChannelTarget::Main, msg_id, $msg);
-                return None;
-            }};
-        }
-
-        let url = url::Url::parse(input).ok()?;
-
-        match url.scheme() {
-            "http" | "https" if matches!(url.domain(), Some("open.spotify.com")) => {}
-            _ => nope!("only spotify URLs are allowed"),
-        };
-
-        if let Some(id) = url
-            .path()
-            .strip_prefix("/track/")
-            .filter(|c| c.len() == 22)
-            .map(|id| format!("spotify:track:{id}"))
-            .and_then(|id| SpotifyId::from_uri(&id).ok())
-        {
-            return Some(id);
-        }
-
-        nope!("invalid spotify URN")
+    async fn queued_by(requests: &UnboundedSender<Query>, user_id: &UserIdRef) -> usize {
+        let (tx, rx) = oneshot::channel();
+        let _ = requests.send(Query::Queue(tx));
+        let QueueSnapshot { queue, .. } = rx.await.expect("gui is running");
+        queue
+            .iter()
+            .filter(|req| req.user.id.as_str() == user_id.as_str())
+            .count()
     }
 
     async fn handle_search(
@@ -366,20 +1050,16 @@ impl Bot {
         user_id: &UserIdRef,
         msg_id: &MsgIdRef,
     ) {
-        let results = match self
-            .spotify
-            // TODO make this stuff configurable
-            .search(
-                req,
-                SearchType::Track,
-                Some(Market::Country(Country::UnitedStates)),
-                None,
-                Some(9),
-                None,
-            )
-            .await
-        {
-            Ok(results) => results,
+        let mut query = SearchQuery::parse(req);
+        let mut items = self.search_tracks(&query).await;
+        // `stand by me` isn't a song called `stand` by `me`, so the whole thing is tried as well
+        if query.artist.is_some() && matches!(&items, Ok(items) if items.is_empty()) {
+            query = SearchQuery::plain(req);
+            items = self.search_tracks(&query).await;
+        }
+
+        let items = match items {
+            Ok(items) => items,
             Err(err) => {
                 log::error!("cannot lookup item: {err}");
                 self.writer
@@ -388,8 +1068,7 @@ impl Bot {
             }
         };
 
-        let SearchResult::Tracks(Page { items, .. }) = results else { return };
-
+        self.expired.remove(user_id);
         self.selection.remove(user_id);
         let selection = self
             .selection
@@ -437,4 +1116,193 @@ impl Bot {
             self.selection.remove(user_id);
         }
     }
+
+    // these are filtered and the best matches come first
+    async fn search_tracks(&self, query: &SearchQuery<'_>) -> ClientResult<Vec<FullTrack>> {
+        let results = self
+            .spotify
+            .search(
+                &query.to_query(),
+                SearchType::Track,
+                Some(Market::Country(self.search.market)),
+                None,
+                Some(self.search.limit),
+                None,
+            )
+            .await?;
+
+        let SearchResult::Tracks(Page { mut items, .. }) = results else { return Ok(Vec::new()) };
+        items.retain(|item| self.search.allows(item));
+        items.sort_by_key(|item| query.rank(item));
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use librespot::core::SessionConfig;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::{
+        request::{Artist, Track},
+        twitch::fake_server::FakeServer,
+    };
+
+    const TRACK: &str = "4uLU6hMCjMI75M1A2tKUQC";
+
+    fn track_info(track_id: SpotifyId) -> TrackInfo {
+        TrackInfo {
+            track: Arc::new(Track {
+                id: track_id,
+                name: String::from("Song"),
+                artists: vec![Artist {
+                    id: SpotifyId::from_uri("spotify:artist:0OdUWJ0sBjDrqHygGUXeCF").unwrap(),
+                    name: String::from("Someone"),
+                }],
+                duration: 180_000,
+            }),
+            image_id: None,
+            lyrics: Default::default(),
+        }
+    }
+
+    async fn next<T>(rx: &mut UnboundedReceiver<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .expect("a message in time")
+            .expect("the bot is running")
+    }
+
+    // the bot, connected to a fake server, with spotify and the gui stubbed out
+    struct Harness {
+        server: FakeServer,
+        produced: UnboundedReceiver<SynthEvent<Request>>,
+        // everything the bot asked the gui, except for the queue
+        queries: UnboundedReceiver<Query>,
+    }
+
+    impl Harness {
+        async fn start() -> Self {
+            let mut server = FakeServer::start().await;
+            let config = twitch::Config {
+                name: String::from("bot"),
+                pass: String::from("oauth:hunter2"),
+                main_channel: String::from("main"),
+                spam_channel: String::from("spam"),
+                address: server.address.clone(),
+                tls: false,
+                ping_timeout: Duration::from_secs(60),
+            };
+
+            let (events_tx, events) = unbounded_channel();
+            let (writer, writer_rx) = unbounded_channel();
+            tokio::spawn(twitch::connect(
+                config.clone(),
+                events_tx,
+                writer_rx,
+                twitch::Status::default(),
+            ));
+
+            let (produce, produced) = unbounded_channel();
+            let (requests, mut requests_rx) = unbounded_channel();
+            let (commands, _) = unbounded_channel();
+            let mut bot = Bot::new(
+                config,
+                Settings::from_env(),
+                events,
+                twitch::Writer::new(writer),
+                produce,
+                requests,
+                commands,
+                Session::new(SessionConfig::default(), None),
+                ClientCredsSpotify::default(),
+                db::Connection::open(":memory:").unwrap(),
+            );
+
+            // spotify isn't asked, this is the only track there is
+            let track_id = SpotifyId::from_uri(&format!("spotify:track:{TRACK}")).unwrap();
+            let tracks = [(track_id, track_info(track_id))].into_iter().collect();
+            bot.tracks = TrackSource::Fixed(Arc::new(tracks));
+            tokio::spawn(bot.process());
+
+            // this stands in for the gui, with nothing queued
+            let (queries_tx, queries) = unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(query) = requests_rx.recv().await {
+                    match query {
+                        Query::Queue(resp) => {
+                            let _ = resp.send(QueueSnapshot {
+                                remaining: Duration::ZERO,
+                                queue: VecDeque::new(),
+                            });
+                        }
+                        query => {
+                            let _ = queries_tx.send(query);
+                        }
+                    }
+                }
+            });
+
+            server.expect("JOIN #spam").await;
+            Self {
+                server,
+                produced,
+                queries,
+            }
+        }
+
+        // this returns the id of the ~req message, and what was queued for it
+        async fn request(&mut self) -> (String, Request) {
+            let data = format!("~req spotify:track:{TRACK}");
+            let msg_id = self.server.say("viewer", "", &data);
+            let SynthEvent::Organic(req) = next(&mut self.produced).await else {
+                panic!("the request wasn't queued")
+            };
+            (msg_id, req)
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_announced_and_queued() {
+        let mut harness = Harness::start().await;
+        let (msg_id, req) = harness.request().await;
+        let track_id = SpotifyId::from_uri(&format!("spotify:track:{TRACK}")).unwrap();
+        assert_eq!(req.track.id, track_id);
+
+        let added = format!("added Song by Someone @ https://open.spotify.com/track/{TRACK}");
+        let announced = harness.server.expect("PRIVMSG #main").await;
+        assert!(announced.ends_with(&format!(":{added}")), "{announced}");
+
+        let reply = harness.server.expect("PRIVMSG #spam").await;
+        assert!(
+            reply.contains(&format!("reply-parent-msg-id={msg_id}")),
+            "{reply}"
+        );
+        assert!(reply.ends_with(&format!(":{added}")), "{reply}");
+    }
+
+    #[tokio::test]
+    async fn deleted_requests_are_removed() {
+        let mut harness = Harness::start().await;
+        let (msg_id, req) = harness.request().await;
+
+        harness.server.delete(&msg_id);
+        let Query::RemoveRequests(ids) = next(&mut harness.queries).await else {
+            panic!("the request wasn't removed")
+        };
+        assert_eq!(ids, vec![req.id]);
+    }
+
+    #[tokio::test]
+    async fn timed_out_users_are_removed() {
+        let mut harness = Harness::start().await;
+        let (_, req) = harness.request().await;
+
+        harness.server.time_out("viewer");
+        let Query::RemoveUser(user_id) = next(&mut harness.queries).await else {
+            panic!("the user's requests weren't removed")
+        };
+        assert_eq!(user_id, req.user.id);
+    }
 }
@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use hashbrown::HashMap;
+use twitch_message::messages::{types::UserId, UserIdRef};
+
+use crate::util::{env_or, format_remaining};
+
+use super::roles::Role;
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    // how many requests a user can have waiting in the queue
+    pub max_queued: usize,
+    // how long a user has to wait between their requests
+    pub request_gap: Duration,
+    // how long before the same track can be requested again
+    pub track_cooldown: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_queued: 3,
+            request_gap: Duration::from_secs(60),
+            track_cooldown: Duration::from_secs(2 * 60 * 60),
+        }
+    }
+}
+
+impl Limits {
+    pub const fn unlimited() -> Self {
+        Self {
+            max_queued: usize::MAX,
+            request_gap: Duration::ZERO,
+            track_cooldown: Duration::ZERO,
+        }
+    }
+
+    // reads `{prefix}_MAX_QUEUED`, `{prefix}_REQUEST_GAP_SECS` and `{prefix}_TRACK_COOLDOWN_SECS`
+    pub fn from_env(prefix: &str, default: Self) -> Self {
+        let key = |name: &str| format!("{prefix}_{name}");
+
+        Self {
+            max_queued: env_or(&key("MAX_QUEUED"), default.max_queued),
+            request_gap: Duration::from_secs(env_or(
+                &key("REQUEST_GAP_SECS"),
+                default.request_gap.as_secs(),
+            )),
+            track_cooldown: Duration::from_secs(env_or(
+                &key("TRACK_COOLDOWN_SECS"),
+                default.track_cooldown.as_secs(),
+            )),
+        }
+    }
+
+    // subscribers and vips get an extra slot, moderators aren't limited
+    pub fn role_overrides_from_env() -> BTreeMap<Role, Self> {
+        let extended = Self {
+            max_queued: 4,
+            ..Self::default()
+        };
+
+        [
+            (Role::Subscriber, extended.clone()),
+            (Role::Vip, extended),
+            (Role::Moderator, Self::unlimited()),
+        ]
+        .into_iter()
+        .map(|(role, default)| {
+            let prefix = format!("LIMIT_{role}", role = role.as_str().to_uppercase());
+            (role, Self::from_env(&prefix, default))
+        })
+        .collect()
+    }
+}
+
+pub enum Rejection {
+    TooManyQueued { max: usize },
+    TooSoon { remaining: Duration },
+    Cooldown { remaining: Duration },
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooManyQueued { max } => {
+                write!(f, "you already have {max} songs in the queue")
+            }
+            Self::TooSoon { remaining } => write!(
+                f,
+                "you can request another song in {remaining}",
+                remaining = format_remaining(*remaining)
+            ),
+            Self::Cooldown { remaining } => write!(
+                f,
+                "that song was requested recently, it can be requested again in {remaining}",
+                remaining = format_remaining(*remaining)
+            ),
+        }
+    }
+}
+
+pub struct Limiter {
+    pub limits: Limits,
+    pub overrides: BTreeMap<Role, Limits>,
+    last_request: HashMap<UserId, Instant>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits, overrides: BTreeMap<Role, Limits>) -> Self {
+        Self {
+            limits,
+            overrides,
+            last_request: HashMap::new(),
+        }
+    }
+
+    // roles without an override use the closest lower role's limits
+    pub fn limits_for(&self, role: Role) -> &Limits {
+        self.overrides
+            .range(..=role)
+            .next_back()
+            .map_or(&self.limits, |(_, limits)| limits)
+    }
+
+    pub fn check(
+        &self,
+        user_id: &UserIdRef,
+        role: Role,
+        queued: usize,
+        last_requested: Option<time::OffsetDateTime>,
+    ) -> Result<(), Rejection> {
+        fn remaining(limit: Duration, elapsed: Duration) -> Option<Duration> {
+            limit.checked_sub(elapsed).filter(|c| !c.is_zero())
+        }
+
+        let limits = self.limits_for(role);
+
+        if queued >= limits.max_queued {
+            return Err(Rejection::TooManyQueued {
+                max: limits.max_queued,
+            });
+        }
+
+        if let Some(remaining) = self
+            .last_request
+            .get(user_id)
+            .and_then(|last| remaining(limits.request_gap, last.elapsed()))
+        {
+            return Err(Rejection::TooSoon { remaining });
+        }
+
+        if let Some(remaining) = last_requested.and_then(|added_on| {
+            let elapsed = time::OffsetDateTime::now_utc() - added_on;
+            remaining(
+                limits.track_cooldown,
+                elapsed.try_into().unwrap_or_default(),
+            )
+        }) {
+            return Err(Rejection::Cooldown { remaining });
+        }
+
+        Ok(())
+    }
+
+    pub fn record(&mut self, user_id: &UserIdRef) {
+        self.last_request.insert(user_id.to_owned(), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> Limiter {
+        let limits = |max_queued| Limits {
+            max_queued,
+            ..Limits::default()
+        };
+        Limiter::new(
+            limits(1),
+            [(Role::Vip, limits(3)), (Role::Moderator, limits(10))]
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn default_role_limits() {
+        let limiter = Limiter::new(Limits::default(), Limits::role_overrides_from_env());
+        for (role, expected) in [
+            (Role::Viewer, 3),
+            (Role::Subscriber, 4),
+            (Role::Vip, 4),
+            (Role::Moderator, usize::MAX),
+        ] {
+            assert_eq!(limiter.limits_for(role).max_queued, expected, "{role}");
+        }
+    }
+
+    #[test]
+    fn limits_fall_back_to_lower_roles() {
+        let limiter = limiter();
+        for (role, expected) in [
+            (Role::Viewer, 1),
+            (Role::Subscriber, 1),
+            (Role::Vip, 3),
+            (Role::Moderator, 10),
+            (Role::Broadcaster, 10),
+        ] {
+            assert_eq!(limiter.limits_for(role).max_queued, expected, "{role}");
+        }
+    }
+
+    #[test]
+    fn check_uses_role_limits() {
+        let limiter = limiter();
+        let user_id = UserId::from(String::from("1"));
+
+        assert!(limiter.check(&user_id, Role::Viewer, 0, None).is_ok());
+        assert!(matches!(
+            limiter.check(&user_id, Role::Viewer, 1, None),
+            Err(Rejection::TooManyQueued { max: 1 })
+        ));
+        assert!(limiter.check(&user_id, Role::Broadcaster, 9, None).is_ok());
+    }
+
+    #[test]
+    fn request_gap_and_cooldown() {
+        let mut limiter = limiter();
+        let user_id = UserId::from(String::from("1"));
+
+        limiter.record(&user_id);
+        assert!(matches!(
+            limiter.check(&user_id, Role::Viewer, 0, None),
+            Err(Rejection::TooSoon { .. })
+        ));
+
+        let other = UserId::from(String::from("2"));
+        let recently = time::OffsetDateTime::now_utc() - time::Duration::minutes(5);
+        assert!(matches!(
+            limiter.check(&other, Role::Viewer, 0, Some(recently)),
+            Err(Rejection::Cooldown { .. })
+        ));
+
+        let long_ago = time::OffsetDateTime::now_utc() - time::Duration::days(1);
+        assert!(limiter
+            .check(&other, Role::Viewer, 0, Some(long_ago))
+            .is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+use librespot::core::SpotifyId;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Link {
+    Track(SpotifyId),
+    Album(SpotifyId),
+    Playlist(SpotifyId),
+    Artist(SpotifyId),
+}
+
+impl Link {
+    // what can be used depends on the command, so the callers explain this themselves
+    pub const UNSUPPORTED: &'static str = "unsupported kind of spotify link";
+
+    // this accepts `spotify:kind:id` URIs and `https://open.spotify.com/kind/id` URLs,
+    // including the localized `intl-xx` paths and share links.
+    //
+    // anything that doesn't look like a URI or URL is Ok(None)
+    pub fn parse(input: &str) -> Result<Option<Self>, &'static str> {
+        let input = input.trim();
+
+        if let Some(uri) = input.strip_prefix("spotify:") {
+            let (kind, id) = uri.split_once(':').ok_or("invalid spotify URI")?;
+            return Self::from_parts(kind, id).map(Some);
+        }
+
+        if !input.starts_with("https://") && !input.starts_with("http://") {
+            return Ok(None);
+        }
+
+        let url = url::Url::parse(input).map_err(|_| "invalid URL")?;
+        if !matches!(url.domain(), Some("open.spotify.com")) {
+            return Err("only spotify URLs are allowed");
+        }
+
+        let mut segments = url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .filter(|c| !c.is_empty())
+            .skip_while(|c| c.starts_with("intl-") || *c == "embed");
+
+        let (Some(kind), Some(id)) = (segments.next(), segments.next()) else {
+            return Err("invalid spotify URL")
+        };
+
+        Self::from_parts(kind, id).map(Some)
+    }
+
+    pub fn from_parts(kind: &str, id: &str) -> Result<Self, &'static str> {
+        if id.len() != 22 || !id.bytes().all(|c| c.is_ascii_alphanumeric()) {
+            return Err("invalid spotify URN");
+        }
+
+        let link = match kind {
+            "track" => Self::Track,
+            "album" => Self::Album,
+            "playlist" => Self::Playlist,
+            "artist" => Self::Artist,
+            _ => return Err(Self::UNSUPPORTED),
+        };
+
+        SpotifyId::from_uri(&format!("spotify:{kind}:{id}"))
+            .map(link)
+            .map_err(|_| "invalid spotify URN")
+    }
+
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Track(..) => "track",
+            Self::Album(..) => "album",
+            Self::Playlist(..) => "playlist",
+            Self::Artist(..) => "artist",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4uLU6hMCjMI75M1A2tKUQC";
+
+    fn id(kind: &str) -> SpotifyId {
+        SpotifyId::from_uri(&format!("spotify:{kind}:{ID}")).unwrap()
+    }
+
+    #[test]
+    fn parse_uris() {
+        let parsed = |kind: &str| Link::parse(&format!("spotify:{kind}:{ID}"));
+        assert_eq!(parsed("track"), Ok(Some(Link::Track(id("track")))));
+        assert_eq!(parsed("album"), Ok(Some(Link::Album(id("album")))));
+        assert_eq!(parsed("playlist"), Ok(Some(Link::Playlist(id("playlist")))));
+        assert_eq!(parsed("artist"), Ok(Some(Link::Artist(id("artist")))));
+        assert_eq!(parsed("episode"), Err(Link::UNSUPPORTED));
+        assert!(Link::parse("spotify:track").is_err());
+        assert!(Link::parse("spotify:track:tooshort").is_err());
+    }
+
+    #[test]
+    fn parse_urls() {
+        let track = Ok(Some(Link::Track(id("track"))));
+        for url in [
+            format!("https://open.spotify.com/track/{ID}"),
+            format!("  https://open.spotify.com/track/{ID}  "),
+            format!("http://open.spotify.com/track/{ID}"),
+            format!("https://open.spotify.com/track/{ID}?si=0123456789abcdef"),
+            format!("https://open.spotify.com/track/{ID}/"),
+            format!("https://open.spotify.com/intl-de/track/{ID}"),
+            format!("https://open.spotify.com/intl-pt/track/{ID}?si=abc&nd=1"),
+            format!("https://open.spotify.com/embed/track/{ID}"),
+        ] {
+            assert_eq!(Link::parse(&url), track, "{url}");
+        }
+
+        assert_eq!(
+            Link::parse(&format!("https://open.spotify.com/playlist/{ID}?si=abc")),
+            Ok(Some(Link::Playlist(id("playlist"))))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_other_links() {
+        assert_eq!(Link::parse("some song by someone"), Ok(None));
+        assert_eq!(Link::parse(""), Ok(None));
+        assert!(Link::parse(&format!("https://example.com/track/{ID}")).is_err());
+        assert!(Link::parse("https://open.spotify.com/").is_err());
+        assert!(Link::parse("https://open.spotify.com/track/").is_err());
+        assert!(Link::parse(&format!("https://open.spotify.com/show/{ID}")).is_err());
+    }
+}
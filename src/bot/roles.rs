@@ -0,0 +1,186 @@
+use twitch_message::messages::Privmsg;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    #[default]
+    Viewer,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl Role {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Subscriber => "subscriber",
+            Self::Vip => "vip",
+            Self::Moderator => "moderator",
+            Self::Broadcaster => "broadcaster",
+        }
+    }
+
+    // this is the highest role found in the message's badges
+    pub fn of(msg: &Privmsg<'_>) -> Self {
+        msg.tags
+            .get("badges")
+            .map(Self::from_badges)
+            .unwrap_or_default()
+    }
+
+    // badges look like `broadcaster/1,subscriber/12`
+    pub fn from_badges(badges: &str) -> Self {
+        badges
+            .split(',')
+            .map(|badge| badge.split_once('/').map_or(badge, |(name, _)| name))
+            .filter_map(|name| match name {
+                "broadcaster" => Some(Self::Broadcaster),
+                "moderator" => Some(Self::Moderator),
+                "vip" => Some(Self::Vip),
+                "subscriber" | "founder" => Some(Self::Subscriber),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Song,
+    Queue,
+    Remove,
+    Request,
+    VoteSkip,
+    Skip,
+    Pause,
+    Resume,
+    Volume,
+    Ban,
+    Unban,
+    Top,
+    Stats,
+    MyStats,
+}
+
+impl Command {
+    // this splits off the arguments following the command
+    pub fn parse(input: &str) -> Option<(Self, &str)> {
+        let (head, tail) = input.trim().split_once(' ').unwrap_or((input.trim(), ""));
+        let command = match head {
+            "~song" => Self::Song,
+            "~queue" => Self::Queue,
+            "~wrongsong" | "~remove" => Self::Remove,
+            "~req" => Self::Request,
+            "~voteskip" => Self::VoteSkip,
+            "~skip" => Self::Skip,
+            "~pause" => Self::Pause,
+            "~resume" => Self::Resume,
+            "~volume" => Self::Volume,
+            "~ban" => Self::Ban,
+            "~unban" => Self::Unban,
+            "~top" => Self::Top,
+            "~stats" => Self::Stats,
+            "~mystats" => Self::MyStats,
+            _ => return None,
+        };
+        Some((command, tail.trim()))
+    }
+
+    pub const fn min_role(&self) -> Role {
+        match self {
+            Self::Song
+            | Self::Queue
+            | Self::Remove
+            | Self::Request
+            | Self::VoteSkip
+            | Self::Top
+            | Self::Stats
+            | Self::MyStats => Role::Viewer,
+            Self::Skip | Self::Pause | Self::Resume | Self::Volume | Self::Ban | Self::Unban => {
+                Role::Moderator
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use twitch_message::{messages::TwitchMessage, IntoStatic as _, ParseResult};
+
+    use super::*;
+
+    fn privmsg(badges: &str, data: &str) -> Privmsg<'static> {
+        let line = format!(
+            "@badge-info=;badges={badges};color=#1E90FF;display-name=someone;emotes=;\
+             id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=1;subscriber=0;\
+             tmi-sent-ts=1507246572675;turbo=0;user-id=2;user-type= \
+             :someone!someone@someone.tmi.twitch.tv PRIVMSG #channel :{data}"
+        );
+        let ParseResult { message, .. } = twitch_message::parse(&line).expect("valid line");
+        match message.as_enum() {
+            TwitchMessage::Privmsg(msg) => msg.into_static(),
+            _ => panic!("not a privmsg: {line}"),
+        }
+    }
+
+    #[test]
+    fn role_from_badges() {
+        for (badges, expected) in [
+            ("", Role::Viewer),
+            ("premium/1", Role::Viewer),
+            ("subscriber/12", Role::Subscriber),
+            ("founder/0", Role::Subscriber),
+            ("vip/1", Role::Vip),
+            ("moderator/1", Role::Moderator),
+            ("broadcaster/1", Role::Broadcaster),
+            ("subscriber/3,vip/1", Role::Vip),
+            ("moderator/1,subscriber/24,premium/1", Role::Moderator),
+            ("subscriber/6,broadcaster/1,moderator/1", Role::Broadcaster),
+        ] {
+            assert_eq!(Role::of(&privmsg(badges, "hello")), expected, "{badges}");
+        }
+    }
+
+    #[test]
+    fn parse_command() {
+        let msg = privmsg("", "  ~req some song  ");
+        assert_eq!(
+            Command::parse(&msg.data),
+            Some((Command::Request, "some song"))
+        );
+
+        assert_eq!(Command::parse("~wrongsong"), Some((Command::Remove, "")));
+        assert_eq!(Command::parse("~remove"), Some((Command::Remove, "")));
+        assert_eq!(Command::parse("~volume 50"), Some((Command::Volume, "50")));
+        assert_eq!(Command::parse("req something"), None);
+        assert_eq!(Command::parse("~requests"), None);
+        assert_eq!(Command::parse(""), None);
+    }
+
+    #[test]
+    fn command_min_role() {
+        for command in [
+            Command::Song,
+            Command::Request,
+            Command::VoteSkip,
+            Command::MyStats,
+        ] {
+            assert_eq!(command.min_role(), Role::Viewer);
+        }
+        for command in [Command::Skip, Command::Volume, Command::Ban, Command::Unban] {
+            assert_eq!(command.min_role(), Role::Moderator);
+        }
+
+        let (command, _) = Command::parse("~skip").unwrap();
+        assert!(Role::of(&privmsg("vip/1", "~skip")) < command.min_role());
+        assert!(Role::of(&privmsg("broadcaster/1", "~skip")) >= command.min_role());
+    }
+}
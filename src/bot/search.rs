@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use rspotify::model::{Country, FullTrack};
+
+use crate::util::env_or;
+
+#[derive(Clone, Debug)]
+pub struct SearchRules {
+    pub market: Country,
+    // how many results are asked for
+    pub limit: u32,
+    // explicit tracks are skipped in clean mode
+    pub clean: bool,
+    // tracks longer than this are skipped
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for SearchRules {
+    fn default() -> Self {
+        Self {
+            market: Country::UnitedStates,
+            limit: 9,
+            clean: false,
+            max_duration: None,
+        }
+    }
+}
+
+impl SearchRules {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        // the country is the ISO 3166-1 alpha-2 code, e.g. `US`
+        let market = std::env::var("SEARCH_MARKET")
+            .ok()
+            .and_then(|code| serde_json::from_value(serde_json::Value::String(code)).ok())
+            .unwrap_or(default.market);
+
+        Self {
+            market,
+            limit: env_or("SEARCH_LIMIT", default.limit),
+            clean: env_or("SEARCH_CLEAN", default.clean),
+            max_duration: std::env::var("SEARCH_MAX_DURATION_SECS")
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .map(Duration::from_secs)
+                .or(default.max_duration),
+        }
+    }
+
+    pub fn allows(&self, track: &FullTrack) -> bool {
+        if self.clean && track.explicit {
+            return false;
+        }
+        self.max_duration.map_or(true, |max| track.duration <= max)
+    }
+}
+
+// `title by artist` searches for the title and artist separately.
+// titles can have `by` in them too, so the caller tries a `plain` query if this finds nothing
+pub struct SearchQuery<'a> {
+    pub title: &'a str,
+    pub artist: Option<&'a str>,
+}
+
+impl<'a> SearchQuery<'a> {
+    pub fn parse(input: &'a str) -> Self {
+        match input.rsplit_once(" by ") {
+            Some((title, artist)) if !title.trim().is_empty() && !artist.trim().is_empty() => {
+                Self {
+                    title: title.trim(),
+                    artist: Some(artist.trim()),
+                }
+            }
+            _ => Self::plain(input),
+        }
+    }
+
+    // this searches for everything as it was typed
+    pub fn plain(input: &'a str) -> Self {
+        Self {
+            title: input.trim(),
+            artist: None,
+        }
+    }
+
+    pub fn to_query(&self) -> String {
+        match self.artist {
+            Some(artist) => format!(r#"track:"{title}" artist:"{artist}""#, title = self.title),
+            None => self.title.to_string(),
+        }
+    }
+
+    // lower is better
+    pub fn rank(&self, track: &FullTrack) -> usize {
+        let title = track.name.eq_ignore_ascii_case(self.title);
+        let artist = self.artist.map_or(false, |artist| {
+            track
+                .artists
+                .iter()
+                .any(|c| c.name.eq_ignore_ascii_case(artist))
+        });
+
+        match (title, artist) {
+            (true, true) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (false, false) => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> (&str, Option<&str>) {
+        let query = SearchQuery::parse(input);
+        (query.title, query.artist)
+    }
+
+    #[test]
+    fn parse_title_by_artist() {
+        assert_eq!(
+            parsed("  never gonna give you up  "),
+            ("never gonna give you up", None)
+        );
+        assert_eq!(
+            parsed("bye bye bye by nsync"),
+            ("bye bye bye", Some("nsync"))
+        );
+        assert_eq!(parsed("drive by by train"), ("drive by", Some("train")));
+        // this is ambiguous, and is why the caller falls back to `plain`
+        assert_eq!(parsed("stand by me"), ("stand", Some("me")));
+    }
+
+    #[test]
+    fn parse_needs_both_sides() {
+        assert_eq!(parsed("by the way"), ("by the way", None));
+        assert_eq!(parsed("goodbye"), ("goodbye", None));
+        assert_eq!(parsed("something by "), ("something by", None));
+        assert_eq!(parsed(" by someone"), ("by someone", None));
+        assert_eq!(parsed("standby me"), ("standby me", None));
+    }
+
+    #[test]
+    fn to_query_uses_field_filters() {
+        assert_eq!(
+            SearchQuery::parse("bye bye bye by nsync").to_query(),
+            r#"track:"bye bye bye" artist:"nsync""#
+        );
+        assert_eq!(
+            SearchQuery::parse("stand by me").to_query(),
+            r#"track:"stand" artist:"me""#
+        );
+        assert_eq!(
+            SearchQuery::plain(" stand by me ").to_query(),
+            "stand by me"
+        );
+        assert_eq!(SearchQuery::parse("by the way").to_query(), "by the way");
+    }
+}
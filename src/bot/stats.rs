@@ -0,0 +1,35 @@
+use crate::db::Window;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TopKind {
+    #[default]
+    Tracks,
+    Artists,
+}
+
+impl TopKind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tracks => "tracks",
+            Self::Artists => "artists",
+        }
+    }
+}
+
+// this is `[tracks|artists] [day|week|month|year|all]` in any order
+pub fn parse_top(args: &str) -> Result<(TopKind, Window), &'static str> {
+    let (mut kind, mut window) = (TopKind::default(), Window::default());
+    for arg in args.split_whitespace() {
+        match arg {
+            "tracks" | "songs" => kind = TopKind::Tracks,
+            "artists" => kind = TopKind::Artists,
+            "day" | "24h" => window = Window::Day,
+            "week" => window = Window::Week,
+            "month" => window = Window::Month,
+            "year" => window = Window::Year,
+            "all" => window = Window::All,
+            _ => return Err("usage: ~top [tracks|artists] [day|week|month|year|all]"),
+        }
+    }
+    Ok((kind, window))
+}
@@ -0,0 +1,26 @@
+#[cfg(test)]
+use std::sync::Arc;
+
+#[cfg(test)]
+use hashbrown::HashMap;
+use librespot::core::{session::Session, spotify_id::SpotifyId};
+
+use crate::request::TrackInfo;
+
+// where requested tracks are looked up, the tests can't reach spotify so they bring their own
+#[derive(Clone)]
+pub enum TrackSource {
+    Spotify(Session),
+    #[cfg(test)]
+    Fixed(Arc<HashMap<SpotifyId, TrackInfo>>),
+}
+
+impl TrackSource {
+    pub async fn fetch(&self, track_id: SpotifyId) -> Option<TrackInfo> {
+        match self {
+            Self::Spotify(session) => TrackInfo::fetch(session, track_id).await.ok(),
+            #[cfg(test)]
+            Self::Fixed(tracks) => tracks.get(&track_id).cloned(),
+        }
+    }
+}
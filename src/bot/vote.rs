@@ -0,0 +1,199 @@
+use std::time::{Duration, Instant};
+
+use hashbrown::{HashMap, HashSet};
+use twitch_message::messages::{types::UserId, UserIdRef};
+
+use crate::util::env_or;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Threshold {
+    // this many votes are needed
+    Count(usize),
+    // this percentage of the recently active chatters need to vote
+    Percent(f32),
+}
+
+impl Threshold {
+    pub fn required(&self, active: usize) -> usize {
+        match *self {
+            Self::Count(count) => count,
+            Self::Percent(percent) => (active as f32 * percent / 100.0).ceil() as usize,
+        }
+        .max(1)
+    }
+}
+
+// this is either a count (`5`) or a percentage (`30%`)
+impl std::str::FromStr for Threshold {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            return percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|percent: &f32| (0.0..=100.0).contains(percent))
+                .map(Self::Percent)
+                .ok_or("invalid percentage");
+        }
+        s.parse().map(Self::Count).map_err(|_| "invalid count")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VoteSkipRules {
+    pub threshold: Threshold,
+    // how long since their last message a chatter is considered active
+    pub active_window: Duration,
+}
+
+impl Default for VoteSkipRules {
+    fn default() -> Self {
+        Self {
+            threshold: Threshold::Percent(50.0),
+            active_window: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl VoteSkipRules {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            threshold: env_or("VOTESKIP_THRESHOLD", default.threshold),
+            active_window: Duration::from_secs(env_or(
+                "VOTESKIP_ACTIVE_WINDOW_SECS",
+                default.active_window.as_secs(),
+            )),
+        }
+    }
+}
+
+pub struct Tally {
+    pub votes: usize,
+    pub required: usize,
+}
+
+impl Tally {
+    pub const fn passed(&self) -> bool {
+        self.votes >= self.required
+    }
+}
+
+pub struct VoteSkip {
+    rules: VoteSkipRules,
+    chatters: HashMap<UserId, Instant>,
+    votes: Option<(uuid::Uuid, HashSet<UserId>)>,
+}
+
+impl VoteSkip {
+    pub fn new(rules: VoteSkipRules) -> Self {
+        Self {
+            rules,
+            chatters: HashMap::new(),
+            votes: None,
+        }
+    }
+
+    pub fn seen(&mut self, user_id: &UserIdRef) {
+        let window = self.rules.active_window;
+        self.chatters.retain(|_, seen| seen.elapsed() < window);
+        self.chatters.insert(user_id.to_owned(), Instant::now());
+    }
+
+    // votes are reset when the active request changes
+    pub fn vote(&mut self, request_id: uuid::Uuid, user_id: &UserIdRef) -> Tally {
+        if !matches!(&self.votes, Some((id, _)) if *id == request_id) {
+            self.votes = Some((request_id, HashSet::new()));
+        }
+
+        let (_, voters) = self.votes.as_mut().expect("votes for the active request");
+        voters.insert(user_id.to_owned());
+
+        Tally {
+            votes: voters.len(),
+            required: self.rules.threshold.required(self.chatters.len()),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.votes.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_threshold() {
+        assert!(matches!("5".parse(), Ok(Threshold::Count(5))));
+        assert!(matches!(" 12 ".parse(), Ok(Threshold::Count(12))));
+        assert!(matches!("30%".parse(), Ok(Threshold::Percent(p)) if p == 30.0));
+        assert!(matches!(" 50.5 % ".parse(), Ok(Threshold::Percent(p)) if p == 50.5));
+        assert!(matches!("100%".parse(), Ok(Threshold::Percent(p)) if p == 100.0));
+
+        for invalid in ["", "%", "101%", "-1%", "-1", "2.5", "half", "30%%"] {
+            assert!(invalid.parse::<Threshold>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn required_votes() {
+        assert_eq!(Threshold::Count(3).required(100), 3);
+        assert_eq!(Threshold::Count(3).required(0), 3);
+        assert_eq!(Threshold::Count(0).required(10), 1);
+
+        assert_eq!(Threshold::Percent(50.0).required(4), 2);
+        assert_eq!(Threshold::Percent(50.0).required(5), 3);
+        assert_eq!(Threshold::Percent(100.0).required(7), 7);
+        assert_eq!(Threshold::Percent(0.0).required(7), 1);
+        assert_eq!(Threshold::Percent(50.0).required(0), 1);
+    }
+
+    #[test]
+    fn votes_are_counted_once_per_request() {
+        let mut vote_skip = VoteSkip::new(VoteSkipRules {
+            threshold: Threshold::Count(2),
+            ..VoteSkipRules::default()
+        });
+        let (first, second) = (uuid::Uuid::from_u128(1), uuid::Uuid::from_u128(2));
+        let (a, b) = (
+            UserId::from(String::from("1")),
+            UserId::from(String::from("2")),
+        );
+
+        assert!(!vote_skip.vote(first, &a).passed());
+        let tally = vote_skip.vote(first, &a);
+        assert_eq!((tally.votes, tally.required), (1, 2));
+        assert!(vote_skip.vote(first, &b).passed());
+
+        // a new track starts over
+        let tally = vote_skip.vote(second, &a);
+        assert_eq!(tally.votes, 1);
+
+        vote_skip.reset();
+        assert_eq!(vote_skip.vote(second, &b).votes, 1);
+    }
+
+    #[test]
+    fn percentages_use_active_chatters() {
+        let mut vote_skip = VoteSkip::new(VoteSkipRules {
+            threshold: Threshold::Percent(50.0),
+            ..VoteSkipRules::default()
+        });
+        let users = (1..=4)
+            .map(|n| UserId::from(n.to_string()))
+            .collect::<Vec<_>>();
+        for user in &users {
+            vote_skip.seen(user);
+        }
+
+        let request = uuid::Uuid::from_u128(1);
+        let tally = vote_skip.vote(request, &users[0]);
+        assert_eq!((tally.votes, tally.required), (1, 2));
+        assert!(vote_skip.vote(request, &users[1]).passed());
+    }
+}
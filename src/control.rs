@@ -1,4 +1,8 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use egui::{Align, CentralPanel, FontDefinitions, FontTweak, Layout, Slider, TextStyle, Visuals};
 
@@ -7,23 +11,24 @@ use librespot::{
     playback::player::{Player, PlayerEventChannel},
 };
 
-use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
-    oneshot,
-};
+use hashbrown::HashMap;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use twitch_message::messages::types::UserId;
 
 use crate::{
     async_adapter::Fut,
-    bot::SynthEvent,
+    bot::{PlayerCommand, Query, QueueSnapshot, Removed, SynthEvent},
     db,
     ext::JoinWith,
-    history::History,
+    history::{History, LoadProgress, Lookup},
     image_cache::ImageCache,
     player_state::{NextPlayingState, PlayerState},
     request::Request,
     tab_selection::TabSelection,
-    views::HistoryView,
-    views::{ImageView, QueueView},
+    twitch,
+    views::{BlocklistView, ExportMenu, HistoryView},
+    views::{DateRange, StatsSnapshot, StatsView},
+    views::{ImageView, NoticesMenu, QueueView},
     views::{ListView, RequestView},
     volume_state::VolumeState,
 };
@@ -47,24 +52,40 @@ pub struct Control {
 
     history: History,
     history_fut: Fut<History>,
+    history_progress: Arc<LoadProgress>,
     out_of_band: Vec<Request>,
 
     events: UnboundedReceiver<SynthEvent<Request>>,
-    requests: UnboundedReceiver<oneshot::Sender<Option<Request>>>,
+    requests: UnboundedReceiver<Query>,
+    commands: UnboundedReceiver<PlayerCommand>,
 
     player: Player,
     player_state: PlayerState,
     player_events: PlayerEventChannel,
     next_playing: NextPlayingState,
+    current_play: Option<i64>,
 
     state: ControlState,
 
     tab_view: TabSelection,
 
+    blocklist: Vec<db::Blocked>,
+    blocklist_refreshed: Option<Instant>,
+
+    export_path: String,
+    export_status: Option<String>,
+
+    stats_window: db::Window,
+    stats_dates: DateRange,
+    stats: Option<StatsSnapshot>,
+
+    twitch_status: twitch::Status,
+
     db: db::Connection,
 }
 
 impl Control {
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         cc: &eframe::CreationContext,
         session: Session,
@@ -72,15 +93,22 @@ impl Control {
         volume: VolumeState,
         replay: UnboundedSender<SynthEvent<Request>>,
         events: UnboundedReceiver<SynthEvent<Request>>,
-        requests: UnboundedReceiver<oneshot::Sender<Option<Request>>>,
+        requests: UnboundedReceiver<Query>,
+        commands: UnboundedReceiver<PlayerCommand>,
+        twitch_status: twitch::Status,
+        db: db::Connection,
     ) -> Box<dyn eframe::App> {
         cc.egui_ctx.set_pixels_per_point(2.0);
 
-        // TODO get this from the configuration (or just use dirs)
-        let db = db::Connection::open("history.db");
         Self::load_fonts(&cc.egui_ctx);
 
-        let history_fut = History::load(&session, &db, replay);
+        let history_progress = Arc::<LoadProgress>::default();
+        let history_fut = History::load(
+            &session,
+            &db,
+            replay,
+            Lookup::from_env(Arc::clone(&history_progress)),
+        );
 
         let state = cc
             .storage
@@ -89,6 +117,7 @@ impl Control {
                 volume,
                 always_on_top: false,
                 auto_play: false,
+                fair_queue: false,
             });
 
         Box::new(Self {
@@ -98,21 +127,36 @@ impl Control {
 
             history: History::default(),
             history_fut,
+            history_progress,
             out_of_band: Vec::new(),
 
             events,
             requests,
+            commands,
 
             player_events: player.get_player_event_channel(),
             player,
             player_state: PlayerState::default(),
 
             next_playing: NextPlayingState::default(),
+            current_play: None,
 
             state,
 
             tab_view: TabSelection::default(),
 
+            blocklist: Vec::new(),
+            blocklist_refreshed: None,
+
+            export_path: String::from("history.json"),
+            export_status: None,
+
+            stats_window: db::Window::default(),
+            stats_dates: DateRange::default(),
+            stats: None,
+
+            twitch_status,
+
             db,
         })
     }
@@ -168,20 +212,58 @@ impl Control {
 
     fn display_tab_list(&mut self, ui: &mut egui::Ui) {
         ui.horizontal(|ui| {
-            for tab_view in [TabSelection::Queue, TabSelection::History] {
+            for tab_view in [
+                TabSelection::Queue,
+                TabSelection::History,
+                TabSelection::Blocked,
+                TabSelection::Stats,
+            ] {
                 ui.selectable_value(&mut self.tab_view, tab_view, tab_view.label());
             }
+
+            ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                let state = self.twitch_status.state();
+                ui.colored_label(state.color(), "●")
+                    .on_hover_text(state.describe());
+
+                ui.menu_button("💾", |ui| {
+                    ExportMenu {
+                        path: &mut self.export_path,
+                        status: &mut self.export_status,
+                        db: &self.db,
+                    }
+                    .display(ui)
+                });
+
+                let notices = self.twitch_status.notices();
+                if !notices.is_empty() {
+                    ui.menu_button(format!("⚠ {}", notices.len()), |ui| {
+                        NoticesMenu {
+                            notices: &notices,
+                            status: &self.twitch_status,
+                        }
+                        .display(ui)
+                    });
+                }
+
+                if let Some(status) = &self.export_status {
+                    if ui.small_button("✖").clicked() {
+                        self.export_status.take();
+                    } else {
+                        ui.weak(status);
+                    }
+                }
+            });
         });
 
-        let list_view = ListView {
-            cache: &mut self.cache,
-        };
+        let list_view = ListView::new(&mut self.cache);
 
         match self.tab_view {
             TabSelection::Queue => {
                 QueueView {
                     list_view,
                     queue: &mut self.queue,
+                    active: self.active.as_ref().map(|c| c.request.id),
                     db: &self.db,
                 }
                 .display(ui);
@@ -189,48 +271,213 @@ impl Control {
             TabSelection::History => {
                 HistoryView {
                     list_view,
+                    loading: (!self.history_fut.is_resolved()).then(|| self.history_progress.get()),
                     queue: &mut self.queue,
                     history: &mut self.history,
                     db: &self.db,
                 }
                 .display(ui);
             }
+            TabSelection::Blocked => {
+                // the bot changes this, so it has to be reloaded every so often
+                if self
+                    .blocklist_refreshed
+                    .map_or(true, |c| c.elapsed() >= Duration::from_secs(2))
+                {
+                    self.blocklist = self.db.get_blocklist();
+                    self.blocklist_refreshed.replace(Instant::now());
+                }
+
+                BlocklistView {
+                    blocklist: &mut self.blocklist,
+                    db: &self.db,
+                }
+                .display(ui);
+            }
+            TabSelection::Stats => {
+                StatsView {
+                    window: &mut self.stats_window,
+                    dates: &mut self.stats_dates,
+                    snapshot: &mut self.stats,
+                    db: &self.db,
+                }
+                .display(ui);
+            }
         }
     }
 
     fn read_events(&mut self) {
         while let Ok(req) = self.events.try_recv() {
-            let req = match req {
-                SynthEvent::Synthetic(req) => req,
+            let (req, organic) = match req {
+                SynthEvent::Synthetic(req) => (req, false),
                 SynthEvent::Organic(req) => {
+                    if let Err(err) = self.db.cache_track(&req.info()) {
+                        log::warn!("cannot cache track {:?}: {err}", req.track.id);
+                    }
+
                     let place = if self.history_fut.is_resolved() {
-                        self.db.add_history(&req);
+                        if let Err(err) = self.db.add_history(&req) {
+                            log::warn!("cannot add {} to the history: {err}", req.id);
+                        }
                         &mut self.history.requests
                     } else {
                         &mut self.out_of_band
                     };
                     place.push(req.clone());
-                    req
+                    (req, true)
                 }
             };
 
-            self.db.queue(&req);
             if self.active.is_none() {
+                if let Err(err) = self.db.queue_at(&req, 0) {
+                    log::warn!("cannot persist {}: {err}", req.id);
+                }
                 self.active.replace(Active {
                     play_pos: None,
                     request: req,
                 });
                 continue;
             }
-            self.queue.push_back(req);
+
+            // replayed requests are already in order
+            let position = if self.state.fair_queue && organic {
+                Self::fair_position(&self.queue, &req.user.id)
+            } else {
+                self.queue.len()
+            };
+
+            // the active request is the first persisted entry
+            if let Err(err) = self.db.queue_at(&req, position + 1) {
+                log::warn!("cannot persist {}: {err}", req.id);
+            }
+            self.queue.insert(position, req);
+        }
+    }
+
+    // each user's next request is placed after every other waiting user's request
+    fn fair_position(queue: &VecDeque<Request>, user_id: &UserId) -> usize {
+        let round = queue.iter().filter(|req| req.user.id == *user_id).count();
+
+        let mut rounds = HashMap::<&UserId, usize>::new();
+        let mut position = 0;
+        for (i, req) in queue.iter().enumerate() {
+            let seen = rounds.entry(&req.user.id).or_default();
+            if *seen <= round {
+                position = i + 1;
+            }
+            *seen += 1;
+        }
+        position
+    }
+
+    fn persist_queue_order(&self) {
+        let ids = self
+            .active
+            .iter()
+            .map(|Active { request, .. }| request.id)
+            .chain(self.queue.iter().map(|req| req.id));
+
+        if let Err(err) = self.db.set_queue_order(ids) {
+            log::warn!("cannot update the queue order: {err}");
         }
     }
 
-    // this is how the bot requests the current song
-    // TODO make this way less obscure
+    // this is how the bot requests the current song and the queue
     fn read_requests(&mut self) {
-        while let Ok(resp) = self.requests.try_recv() {
-            let _ = resp.send(self.active.as_mut().map(|c| c.request.clone()));
+        while let Ok(query) = self.requests.try_recv() {
+            match query {
+                Query::Active(resp) => {
+                    let _ = resp.send(self.active.as_ref().map(|c| c.request.clone()));
+                }
+                Query::Queue(resp) => {
+                    let remaining = self
+                        .active
+                        .as_ref()
+                        .map(|Active { request, play_pos }| {
+                            Duration::from_millis(request.track.duration as _)
+                                .saturating_sub(play_pos.unwrap_or_default())
+                        })
+                        .unwrap_or_default();
+
+                    let _ = resp.send(QueueSnapshot {
+                        remaining,
+                        queue: self.queue.clone(),
+                    });
+                }
+                Query::RemoveLatest { user_id, resp } => {
+                    let _ = resp.send(self.remove_latest(&user_id));
+                }
+                Query::RemoveRequests(ids) => self.remove_queued(|req| ids.contains(&req.id)),
+                Query::RemoveUser(user_id) => self.remove_queued(|req| req.user.id == user_id),
+            }
+        }
+    }
+
+    // the active request is left alone, it's already playing
+    fn remove_queued(&mut self, remove: impl Fn(&Request) -> bool) {
+        let (removed, kept) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition::<VecDeque<_>, _>(|req| remove(req));
+        self.queue = kept;
+
+        for req in removed {
+            log::info!(
+                "removed {name} requested by {user}",
+                name = req.track.name,
+                user = req.user.name
+            );
+            if let Err(err) = self.db.remove_from_queue(&req) {
+                log::warn!("cannot remove {} from the queue: {err}", req.id);
+            }
+        }
+    }
+
+    fn remove_latest(&mut self, user_id: &UserId) -> Removed {
+        let latest = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, req)| req.user.id == *user_id)
+            .max_by_key(|(_, req)| req.added_on)
+            .map(|(index, req)| (index, req.added_on));
+
+        let active = self
+            .active
+            .as_ref()
+            .map(|Active { request, .. }| request)
+            .filter(|req| req.user.id == *user_id);
+
+        match (latest, active) {
+            (Some((_, added_on)), Some(active)) if active.added_on > added_on => {
+                Removed::Active(active.clone())
+            }
+            (Some((index, _)), _) => {
+                let req = self.queue.remove(index).expect("valid index");
+                if let Err(err) = self.db.remove_from_queue(&req) {
+                    log::warn!("cannot remove {} from the queue: {err}", req.id);
+                }
+                Removed::Removed(req)
+            }
+            (None, Some(active)) => Removed::Active(active.clone()),
+            (None, None) => Removed::Nothing,
+        }
+    }
+
+    // this is how the bot controls playback, it should mirror the player controls
+    fn read_commands(&mut self, replace: &mut Option<Request>) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                PlayerCommand::Skip => {
+                    if self.active.is_none() {
+                        self.queue.pop_front();
+                    }
+                    *replace = self.queue.pop_front();
+                }
+                PlayerCommand::Stop => self.stop(),
+                PlayerCommand::Pause => self.player.pause(),
+                PlayerCommand::Resume => self.player.play(),
+                PlayerCommand::Volume(volume) => self.state.volume.set(volume),
+            }
         }
     }
 
@@ -299,6 +546,7 @@ impl Control {
                                 request: &item,
                                 queue: &mut self.queue,
                                 auto_play: &mut self.state.auto_play,
+                                fair_queue: &mut self.state.fair_queue,
                                 volume: &mut self.state.volume,
                             }
                             .display(ui, replace);
@@ -310,6 +558,8 @@ impl Control {
                         ui.heading("nothing in queue, add something");
                         ui.horizontal(|ui| {
                             ui.toggle_value(&mut self.state.auto_play, "Auto");
+                            ui.toggle_value(&mut self.state.fair_queue, "Fair")
+                                .on_hover_text("Interleave requests from different users");
                             let mut vol = self.state.volume.volume.lock();
                             ui.add(
                                 Slider::new(&mut *vol, 0.0..=1.0)
@@ -334,6 +584,7 @@ impl Control {
                 cache: &mut self.cache,
                 queue: &mut self.queue,
                 auto_play: &mut self.state.auto_play,
+                fair_queue: &mut self.state.fair_queue,
                 player: &mut self.player,
                 player_state: &self.player_state,
                 volume: &self.state.volume,
@@ -354,12 +605,17 @@ impl Control {
     fn handle_replace(&mut self, replace: Option<Request>) {
         let Some(request) = replace else { return };
 
-        if let Some(Active { request, .. }) = self.active.replace(Active {
+        // anything still playing at this point was cut short
+        self.finish_play(true);
+
+        self.active = Some(Active {
             play_pos: None,
             request,
-        }) {
-            self.db.remove_from_queue(&request);
-        }
+        });
+
+        // skipping without an active request drops the front of the queue,
+        // so this resyncs the whole order rather than removing the old request
+        self.persist_queue_order();
 
         self.player.stop();
         let _ = std::mem::take(&mut self.next_playing);
@@ -383,15 +639,58 @@ impl Control {
         self.player.play();
     }
 
+    // the next request waits to be played, like it does when nothing was active
+    fn stop(&mut self) {
+        self.finish_play(true);
+        if self.active.take().is_none() {
+            return;
+        }
+
+        self.persist_queue_order();
+        self.player.stop();
+        let _ = std::mem::take(&mut self.next_playing);
+    }
+
+    fn start_play(&mut self) {
+        self.finish_play(true);
+
+        let Some(Active { request, .. }) = &self.active else { return };
+        match self.db.start_play(request) {
+            Ok(play) => self.current_play = Some(play),
+            Err(err) => log::warn!("cannot record play of {}: {err}", request.id),
+        }
+    }
+
+    fn finish_play(&mut self, skipped: bool) {
+        let Some(play) = self.current_play.take() else { return };
+
+        let listened = self
+            .active
+            .as_ref()
+            .and_then(|Active { play_pos, .. }| *play_pos)
+            .unwrap_or_default();
+
+        if let Err(err) = self.db.finish_play(play, listened, skipped) {
+            log::warn!("cannot finish play {play}: {err}");
+        }
+    }
+
     fn check_state(&mut self, replace: &mut Option<Request>) {
         match &self.player_state {
             PlayerState::Playing { .. } => {
+                if matches!(self.next_playing, NextPlayingState::StopPlaying) {
+                    self.start_play();
+                }
                 self.next_playing = NextPlayingState::Playing;
             }
             PlayerState::EndOfPlaying { .. }
-                if matches!(self.next_playing, NextPlayingState::Playing)
-                    && self.state.auto_play =>
+                if matches!(self.next_playing, NextPlayingState::Playing) =>
             {
+                self.finish_play(false);
+                if !self.state.auto_play {
+                    return;
+                }
+
                 if let Some(Active { play_pos, .. }) = &mut self.active {
                     let _ = play_pos.take();
                 }
@@ -453,7 +752,9 @@ impl eframe::App for Control {
             self.history.requests.reserve(self.out_of_band.len());
 
             for req in self.out_of_band.drain(..) {
-                self.db.add_history(&req);
+                if let Err(err) = self.db.add_history(&req) {
+                    log::warn!("cannot add {} to the history: {err}", req.id);
+                }
                 self.history.requests.push(req)
             }
         }
@@ -463,6 +764,7 @@ impl eframe::App for Control {
         CentralPanel::default().show(ctx, |ui| {
             // TODO use a projection type for this flow
             let mut replace = None;
+            self.read_commands(&mut replace);
             self.display_active(ui, &mut replace);
             self.check_state(&mut replace);
             self.handle_replace(replace);
@@ -485,12 +787,14 @@ struct ControlState {
     volume: VolumeState,
     always_on_top: bool,
     auto_play: bool,
+    fair_queue: bool,
 }
 
 impl ControlState {
     const VOLUME_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".volume");
     const ALWAYS_ON_TOP_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".auto-play");
     const AUTO_PLAY_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".always-on-top");
+    const FAIR_QUEUE_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".fair-queue");
 
     fn load(storage: &dyn eframe::Storage, volume: VolumeState) -> Self {
         fn get<T>(storage: &dyn eframe::Storage, key: &'static str) -> Option<T>
@@ -508,6 +812,7 @@ impl ControlState {
             volume,
             always_on_top: get(storage, Self::ALWAYS_ON_TOP_KEY).unwrap_or_default(),
             auto_play: get(storage, Self::AUTO_PLAY_KEY).unwrap_or_default(),
+            fair_queue: get(storage, Self::FAIR_QUEUE_KEY).unwrap_or_default(),
         }
     }
 
@@ -515,5 +820,6 @@ impl ControlState {
         storage.set_string(Self::VOLUME_KEY, format!("{:.2}", self.volume.get()));
         storage.set_string(Self::ALWAYS_ON_TOP_KEY, self.auto_play.to_string());
         storage.set_string(Self::AUTO_PLAY_KEY, self.always_on_top.to_string());
+        storage.set_string(Self::FAIR_QUEUE_KEY, self.fair_queue.to_string());
     }
 }
@@ -19,6 +19,7 @@ pub struct ActiveControl<'a> {
 
     pub has_active: bool,
     pub auto_play: &'a mut bool,
+    pub fair_queue: &'a mut bool,
 
     pub player: &'a mut Player,
     pub player_state: &'a PlayerState,
@@ -113,6 +114,7 @@ impl<'a> ActiveControl<'a> {
                 request: self.request,
                 queue: self.queue,
                 auto_play: self.auto_play,
+                fair_queue: self.fair_queue,
                 has_active: self.has_active,
                 volume: self.volume,
             }
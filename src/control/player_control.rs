@@ -11,6 +11,7 @@ pub struct PlayerControl<'a> {
     pub request: &'a Request,
     pub queue: &'a mut VecDeque<Request>,
     pub auto_play: &'a mut bool,
+    pub fair_queue: &'a mut bool,
     pub has_active: bool,
     pub volume: &'a VolumeState,
 }
@@ -47,6 +48,8 @@ impl<'a> PlayerControl<'a> {
                     *replace = self.queue.pop_front();
                 }
                 ui.toggle_value(self.auto_play, "Auto");
+                ui.toggle_value(self.fair_queue, "Fair")
+                    .on_hover_text("Interleave requests from different users");
             });
 
             ui.scope(|ui| {
@@ -1,45 +1,53 @@
 use std::{borrow::Cow, path::Path};
 
+use anyhow::Context as _;
 use egui::Color32;
-use librespot::core::SpotifyId;
+use librespot::core::{spotify_id::SpotifyItemType, SpotifyId};
+
+mod migrations;
+mod plays;
+pub use plays::Play;
+mod queue;
+mod stats;
+pub use stats::{ChannelStats, Ranked, UserStats, Window};
+mod tracks;
+pub use tracks::CachedTrack;
+
+// TODO get this from the configuration (or just use dirs)
+pub const DEFAULT_PATH: &str = "history.db";
 
 pub struct Connection {
     conn: rusqlite::Connection,
 }
 
 impl Connection {
-    pub fn open(path: impl AsRef<Path>) -> Self {
-        let conn = rusqlite::Connection::open(path).expect("valid connection");
-        Self::ensure_table(conn)
-    }
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut conn = rusqlite::Connection::open(path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
 
-    fn ensure_table(conn: rusqlite::Connection) -> Self {
-        static SCHEMA: &str = "pragma foreign_keys = 1;
-            create table if not exists history (
-                spotify_id   blob not null,
-                mistake_id   blob not null unique primary key,
-                sender_id    text not null,
-                sender_name  text not null,
-                sender_color blob not null,
-                plays        integer not null,
-                added_on     blob not null unique,
-                deleted      boolean
-            );
+        migrations::run(&mut conn, path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
 
-            create table if not exists queued (
-                queue blob unique not null,
-                play_order integer unique not null,
-                foreign key(queue) references history(mistake_id)
-            );";
+        let this = Self { conn };
 
-        conn.execute_batch(SCHEMA).expect("valid sql");
+        // older versions could leave gaps in the play order
+        if let Err(err) = this.compact_queue() {
+            log::warn!("cannot compact the queue: {err}");
+        }
+        Ok(this)
+    }
 
-        Self { conn }
+    pub fn path(&self) -> Option<&str> {
+        self.conn.path()
     }
 
     pub fn get_all_history(&self) -> Vec<Item<'static>> {
         self.get_many(
-            "select * from history where deleted = false",
+            "select h.*, (select count(*) from plays as p
+                    where p.spotify_id = h.spotify_id) as play_count
+                from history as h
+                where h.deleted = false",
             (),
             Item::<'static>::from_row,
         )
@@ -47,95 +55,52 @@ impl Connection {
 
     pub fn get_queued(&self) -> Vec<Item<'static>> {
         self.get_many(
-            "select * from queued as q
-                join history h on h.mistake_id = q.queue;",
+            "select h.*, (select count(*) from plays as p
+                    where p.spotify_id = h.spotify_id) as play_count
+                from queued as q
+                join history h on h.mistake_id = q.queue
+                order by q.play_order;",
             rusqlite::named_params! {},
             Item::from_row,
         )
     }
 
-    pub fn queue<'a>(&self, item: impl Into<Item<'a>> + ?Sized) {
-        let Self { conn, .. } = self;
-
-        let item = item.into();
-        let mut stmt = conn
-            .prepare(
-                "insert into queued(queue, play_order)
-                    values(:id, (select count(rowid) from queued))",
-            )
-            .expect("valid sql");
-
-        let _ = stmt.execute(rusqlite::named_params! {":id": item.id});
-
-        self.add_history(item);
-    }
-
-    pub fn remove_from_queue<'a>(&self, item: impl Into<Item<'a>> + ?Sized) -> bool {
-        let Self { conn, .. } = self;
-        let mut stmt = conn
-            .prepare("delete from queued where queue = :id")
-            .expect("valid sql");
-
-        let item = item.into();
-        matches!(
-            stmt.execute(rusqlite::named_params! {":id": item.id}),
-            Ok(1)
-        )
-    }
-
-    pub fn update_play_count(&self, id: uuid::Uuid) {
-        let Self { conn, .. } = self;
-        // TODO just use a view
-        let mut stmt = conn
-            .prepare(
-                "update history set plays =
-                    (select plays from history where id = :id) + 1
-                    where id = :id;",
-            )
-            .expect("valid sql");
-
-        let _ = stmt.execute(rusqlite::named_params! {":id": id});
+    // this is false if the item was already in the history
+    pub fn add_history<'a>(&self, item: impl Into<Item<'a>>) -> rusqlite::Result<bool> {
+        Self::insert_history(&self.conn, &item.into())
     }
 
-    pub fn add_history<'a>(&self, item: impl Into<Item<'a>> + ?Sized) {
-        let Self { conn, .. } = self;
-        let mut stmt = conn
-            .prepare(
-                "insert into history (
-                    spotify_id,
-                    mistake_id,
-                    sender_id,
-                    sender_name,
-                    sender_color,
-                    plays,
-                    added_on,
-                    deleted
-                ) values (
-                    :spotify_id,
-                    :mistake_id,
-                    :sender_id,
-                    :sender_name,
-                    :sender_color,
-                    :plays,
-                    :added_on,
-                    :deleted
-                ) on conflict(mistake_id)
-                    do update set plays = plays + 1
-                    where deleted = false;",
-            )
-            .expect("valid sql");
-
-        let item = item.into();
-        let _ = stmt.execute(rusqlite::named_params! {
+    fn insert_history(conn: &rusqlite::Connection, item: &Item<'_>) -> rusqlite::Result<bool> {
+        let mut stmt = conn.prepare(
+            "insert into history (
+                spotify_id,
+                mistake_id,
+                sender_id,
+                sender_name,
+                sender_color,
+                added_on,
+                deleted
+            ) values (
+                :spotify_id,
+                :mistake_id,
+                :sender_id,
+                :sender_name,
+                :sender_color,
+                :added_on,
+                :deleted
+            ) on conflict(mistake_id) do nothing;",
+        )?;
+
+        let inserted = stmt.execute(rusqlite::named_params! {
             ":spotify_id": item.spotify_id.to_raw(),
             ":mistake_id": item.id,
             ":sender_id": item.sender_id,
             ":sender_name": item.sender_name,
             ":sender_color": item.sender_color,
-            ":plays": item.plays,
             ":added_on": item.added_on,
             ":deleted": false,
-        });
+        })?;
+        Ok(inserted > 0)
     }
 
     pub fn undelete_item(&self, item: &Item<'_>) -> bool {
@@ -172,15 +137,99 @@ impl Connection {
             .expect("valid query")
     }
 
-    pub fn get_queued_ids(&self) -> Vec<uuid::Uuid> {
+    pub fn last_requested(&self, spotify_id: SpotifyId) -> Option<time::OffsetDateTime> {
         self.get_many(
-            "select queue from queued
-                order by play_order;",
-            rusqlite::named_params! {},
-            |row| row.get("queue"),
+            "select added_on from history
+                where spotify_id = :spotify_id
+                order by added_on desc
+                limit 1;",
+            rusqlite::named_params! {":spotify_id": spotify_id.to_raw()},
+            |row| row.get("added_on"),
+        )
+        .pop()
+    }
+
+    pub fn block(&self, kind: BlockKind, value: &str, added_by: &str) -> bool {
+        let Self { conn, .. } = self;
+        let mut stmt = conn
+            .prepare(
+                "insert into blocklist (kind, value, added_by, added_on)
+                    values (:kind, :value, :added_by, :added_on)
+                    on conflict(kind, value) do nothing;",
+            )
+            .expect("valid sql");
+
+        matches!(
+            stmt.execute(rusqlite::named_params! {
+                ":kind": kind.as_str(),
+                ":value": kind.normalize(value),
+                ":added_by": added_by,
+                ":added_on": time::OffsetDateTime::now_utc(),
+            }),
+            Ok(1)
+        )
+    }
+
+    pub fn unblock(&self, kind: BlockKind, value: &str) -> bool {
+        let Self { conn, .. } = self;
+        let mut stmt = conn
+            .prepare("delete from blocklist where kind = :kind and value = :value;")
+            .expect("valid sql");
+
+        matches!(
+            stmt.execute(rusqlite::named_params! {
+                ":kind": kind.as_str(),
+                ":value": kind.normalize(value),
+            }),
+            Ok(1)
         )
     }
 
+    pub fn get_blocklist(&self) -> Vec<Blocked> {
+        self.get_many(
+            "select * from blocklist order by added_on desc;",
+            (),
+            Blocked::from_row,
+        )
+    }
+
+    // this finds the first entry that blocks the track, its artists or its title
+    pub fn find_blocked(
+        &self,
+        track_id: SpotifyId,
+        artist_ids: impl IntoIterator<Item = SpotifyId>,
+        title: &str,
+    ) -> Option<Blocked> {
+        let uris = std::iter::once((BlockKind::Track, track_id))
+            .chain(artist_ids.into_iter().map(|id| (BlockKind::Artist, id)))
+            .filter_map(|(kind, id)| Some((kind, id.to_uri().ok()?)));
+
+        for (kind, uri) in uris {
+            if let Some(blocked) = self
+                .get_many(
+                    "select * from blocklist where kind = :kind and value = :value;",
+                    rusqlite::named_params! {":kind": kind.as_str(), ":value": uri},
+                    Blocked::from_row,
+                )
+                .pop()
+            {
+                return Some(blocked);
+            }
+        }
+
+        self.get_many(
+            "select * from blocklist
+                where kind = :kind
+                and instr(:title, value) > 0;",
+            rusqlite::named_params! {
+                ":kind": BlockKind::Keyword.as_str(),
+                ":title": title.to_lowercase(),
+            },
+            Blocked::from_row,
+        )
+        .pop()
+    }
+
     fn get_many<T>(
         &self,
         sql: &str,
@@ -220,6 +269,20 @@ impl<'a> From<&'a crate::request::Request> for Item<'a> {
     }
 }
 
+impl<'a> From<&'a crate::history::HistoryItem<'_>> for Item<'a> {
+    fn from(value: &'a crate::history::HistoryItem<'_>) -> Self {
+        Self {
+            id: value.id,
+            spotify_id: value.spotify_id,
+            sender_id: Cow::from(value.user.id.as_str()),
+            sender_name: Cow::from(value.user.name.as_str()),
+            sender_color: Self::convert_color(value.user.color),
+            plays: 0,
+            added_on: value.added_on,
+        }
+    }
+}
+
 impl<'a> Item<'a> {
     pub fn color_from_u32(color: u32) -> Color32 {
         let (r, g, b) = (
@@ -241,13 +304,113 @@ impl Item<'static> {
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get("mistake_id")?,
+            // only tracks are stored, but the raw form doesn't keep the item type
             spotify_id: SpotifyId::from_raw(&row.get::<_, Vec<u8>>("spotify_id")?)
+                .map(|id| SpotifyId {
+                    item_type: SpotifyItemType::Track,
+                    ..id
+                })
                 .map_err(|_| rusqlite::Error::InvalidQuery)?,
             sender_id: row.get::<_, String>("sender_id")?.into(),
             sender_name: row.get::<_, String>("sender_name")?.into(),
             sender_color: row.get("sender_color")?,
-            plays: row.get("plays")?,
+            plays: row.get("play_count")?,
+            added_on: row.get("added_on")?,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Track,
+    Artist,
+    Keyword,
+}
+
+impl BlockKind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Track => "track",
+            Self::Artist => "artist",
+            Self::Keyword => "keyword",
+        }
+    }
+
+    // keywords are matched case-insensitively
+    fn normalize(&self, value: &str) -> String {
+        match self {
+            Self::Keyword => value.trim().to_lowercase(),
+            _ => value.trim().to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for BlockKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "track" => Self::Track,
+            "artist" => Self::Artist,
+            "keyword" => Self::Keyword,
+            _ => return Err("unknown block kind"),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Blocked {
+    pub kind: BlockKind,
+    pub value: String,
+    pub added_by: String,
+    pub added_on: time::OffsetDateTime,
+}
+
+impl Blocked {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            kind: row
+                .get::<_, String>("kind")?
+                .parse()
+                .map_err(|_| rusqlite::Error::InvalidQuery)?,
+            value: row.get("value")?,
+            added_by: row.get("added_by")?,
             added_on: row.get("added_on")?,
         })
     }
 }
+
+// these are shared by the tests of the submodules
+#[cfg(test)]
+mod test_util {
+    use super::*;
+
+    pub fn open() -> Connection {
+        Connection::open(":memory:").expect("in-memory database")
+    }
+
+    pub fn track_id(n: u8) -> SpotifyId {
+        SpotifyId {
+            item_type: SpotifyItemType::Track,
+            ..SpotifyId::from_raw(&[n; 16]).expect("valid id")
+        }
+    }
+
+    // `added_on` is unique in the history table, so every item needs its own
+    pub fn item(
+        n: u8,
+        spotify_id: SpotifyId,
+        sender: &str,
+        added_on: time::OffsetDateTime,
+    ) -> Item<'static> {
+        Item {
+            id: uuid::Uuid::from_u128(n as u128),
+            spotify_id,
+            sender_id: Cow::Owned(format!("{sender}-id")),
+            sender_name: Cow::Owned(sender.to_string()),
+            sender_color: 0,
+            plays: 0,
+            added_on,
+        }
+    }
+}
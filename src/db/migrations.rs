@@ -0,0 +1,360 @@
+use std::path::Path;
+
+use anyhow::Context as _;
+
+// each entry upgrades the schema from version `index` to `index + 1`
+// never change a released entry, append a new one instead
+static MIGRATIONS: &[&str] = &[
+    // 1: the schema from before versioning, so existing tables have to be tolerated
+    "create table if not exists history (
+        spotify_id   blob not null,
+        mistake_id   blob not null unique primary key,
+        sender_id    text not null,
+        sender_name  text not null,
+        sender_color blob not null,
+        plays        integer not null,
+        added_on     blob not null unique,
+        deleted      boolean
+    );
+
+    create table if not exists queued (
+        queue blob unique not null,
+        play_order integer unique not null,
+        foreign key(queue) references history(mistake_id)
+    );
+
+    create table if not exists blocklist (
+        kind     text not null,
+        value    text not null,
+        added_by text not null,
+        added_on blob not null,
+        unique(kind, value)
+    );
+
+    create table if not exists plays (
+        mistake_id  blob not null,
+        spotify_id  blob not null,
+        started_on  blob not null,
+        finished_on blob,
+        listened_ms integer not null default 0,
+        skipped     boolean not null default false,
+        foreign key(mistake_id) references history(mistake_id)
+    );",
+    // 2: play counts are derived from the plays table, so the old counters become plays first.
+    // they're only counts, so each one is recorded as a finished play at the time of the request
+    "with recursive counts(mistake_id, spotify_id, added_on, remaining) as (
+        select h.mistake_id, h.spotify_id, h.added_on,
+            h.plays - (select count(*) from plays as p where p.mistake_id = h.mistake_id)
+            from history as h
+        union all
+        select mistake_id, spotify_id, added_on, remaining - 1
+            from counts where remaining > 1
+    )
+    insert into plays (mistake_id, spotify_id, started_on, finished_on)
+        select mistake_id, spotify_id, added_on, added_on
+            from counts where remaining > 0;
+
+    alter table history drop column plays;",
+    // 3: track metadata, so history doesn't have to be looked up on every start
+    "create table tracks (
+        spotify_id blob not null unique primary key,
+        name       text not null,
+        duration   integer not null,
+        cover      blob,
+        lyrics     text not null,
+        synced     boolean not null,
+        updated_on blob not null
+    );
+
+    create table track_artists (
+        spotify_id blob not null,
+        position   integer not null,
+        artist_id  text not null,
+        name       text not null,
+        unique(spotify_id, position),
+        foreign key(spotify_id) references tracks(spotify_id) on delete cascade
+    );",
+];
+
+pub const LATEST: usize = MIGRATIONS.len();
+
+// this brings the database at `path` up to the latest version, backing it up first
+pub fn run(conn: &mut rusqlite::Connection, path: &Path) -> anyhow::Result<()> {
+    let version = version(conn)?;
+    anyhow::ensure!(
+        version <= LATEST,
+        "{path} is at schema version {version} but this build only supports up to {LATEST}",
+        path = path.display()
+    );
+
+    if version == LATEST {
+        return Ok(());
+    }
+
+    if !is_empty(conn)? {
+        backup(conn, path, version)?;
+    }
+
+    let tx = conn.transaction()?;
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("migrating {} to version {}", path.display(), from + 1);
+        tx.execute_batch(migration)
+            .with_context(|| format!("cannot migrate to version {}", from + 1))?;
+    }
+    // user_version is part of the database header so this is rolled back with the rest
+    tx.pragma_update(None, "user_version", LATEST)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn version(conn: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+fn is_empty(conn: &rusqlite::Connection) -> rusqlite::Result<bool> {
+    conn.query_row("select count(*) = 0 from sqlite_master;", (), |row| {
+        row.get(0)
+    })
+}
+
+fn backup(conn: &rusqlite::Connection, path: &Path, version: usize) -> anyhow::Result<()> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{version}.bak"));
+    let backup = Path::new(&backup);
+
+    // a failed migration leaves the database at the same version, so this is the same backup
+    if backup.exists() {
+        std::fs::remove_file(backup)
+            .with_context(|| format!("cannot replace {}", backup.display()))?;
+    }
+
+    log::info!("backing up {} to {}", path.display(), backup.display());
+    conn.execute(
+        "vacuum into :path;",
+        rusqlite::named_params! {
+            ":path": backup.to_string_lossy().into_owned(),
+        },
+    )
+    .with_context(|| format!("cannot back up {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    // this is the schema from before versioning, as the first release created it
+    const BASELINE: &str = "
+        create table history (
+            spotify_id   blob not null,
+            mistake_id   blob not null unique primary key,
+            sender_id    text not null,
+            sender_name  text not null,
+            sender_color blob not null,
+            plays        integer not null,
+            added_on     blob not null unique,
+            deleted      boolean
+        );
+
+        create table queued (
+            queue blob unique not null,
+            play_order integer unique not null,
+            foreign key(queue) references history(mistake_id)
+        );";
+
+    // a database file that's removed, along with its backups, when dropped
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let name = format!("spotify-mistake-{name}-{}.db", std::process::id());
+            let this = Self(std::env::temp_dir().join(name));
+            this.remove();
+            this
+        }
+
+        fn open(&self) -> rusqlite::Connection {
+            rusqlite::Connection::open(&self.0).unwrap()
+        }
+
+        fn backup(&self, version: usize) -> PathBuf {
+            let mut backup = self.0.as_os_str().to_owned();
+            backup.push(format!(".v{version}.bak"));
+            PathBuf::from(backup)
+        }
+
+        fn remove(&self) {
+            let _ = std::fs::remove_file(&self.0);
+            for version in 0..=LATEST {
+                let _ = std::fs::remove_file(self.backup(version));
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.remove()
+        }
+    }
+
+    fn id(n: u128) -> uuid::Uuid {
+        uuid::Uuid::from_u128(n)
+    }
+
+    fn baseline(db: &TempDb) -> rusqlite::Connection {
+        let conn = db.open();
+        conn.execute_batch(BASELINE).unwrap();
+
+        let mut stmt = conn
+            .prepare(
+                "insert into history values (
+                    :spotify_id, :mistake_id, 'someone-id', 'someone', 0,
+                    :plays, :added_on, false
+                );",
+            )
+            .unwrap();
+        for (n, plays) in [(1, 2), (2, 0), (3, 1)] {
+            stmt.execute(rusqlite::named_params! {
+                ":spotify_id": [n as u8; 16],
+                ":mistake_id": id(n),
+                ":plays": plays,
+                ":added_on": time::OffsetDateTime::UNIX_EPOCH + time::Duration::hours(n as _),
+            })
+            .unwrap();
+        }
+        drop(stmt);
+
+        conn.execute(
+            "insert into queued values (:id, 0);",
+            rusqlite::named_params! { ":id": id(2) },
+        )
+        .unwrap();
+
+        conn
+    }
+
+    fn count(conn: &rusqlite::Connection, sql: &str) -> usize {
+        conn.query_row(sql, (), |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn migrates_the_baseline() {
+        let db = TempDb::new("baseline");
+        let mut conn = baseline(&db);
+
+        run(&mut conn, &db.0).unwrap();
+        assert_eq!(version(&conn).unwrap(), LATEST);
+
+        let history = conn
+            .prepare("select mistake_id from history order by added_on;")
+            .unwrap()
+            .query_map((), |row| row.get::<_, uuid::Uuid>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(history, [id(1), id(2), id(3)]);
+        assert_eq!(count(&conn, "select count(*) from queued;"), 1);
+
+        // the old counters are kept as plays
+        assert_eq!(
+            count(
+                &conn,
+                "select count(*) from pragma_table_info('history') where name = 'plays';"
+            ),
+            0
+        );
+        for (n, plays) in [(1, 2), (2, 0), (3, 1)] {
+            let played: usize = conn
+                .query_row(
+                    "select count(*) from plays where mistake_id = :id;",
+                    rusqlite::named_params! { ":id": id(n) },
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(played, plays);
+        }
+
+        // and the newer tables exist
+        assert_eq!(count(&conn, "select count(*) from tracks;"), 0);
+        assert_eq!(count(&conn, "select count(*) from blocklist;"), 0);
+    }
+
+    #[test]
+    fn backfill_keeps_recorded_plays() {
+        let db = TempDb::new("recorded");
+        let mut conn = baseline(&db);
+
+        // plays were recorded for a while before the schema was versioned
+        conn.execute_batch(
+            "create table plays (
+                mistake_id  blob not null,
+                spotify_id  blob not null,
+                started_on  blob not null,
+                finished_on blob,
+                listened_ms integer not null default 0,
+                skipped     boolean not null default false
+            );",
+        )
+        .unwrap();
+        conn.execute(
+            "insert into plays (mistake_id, spotify_id, started_on, listened_ms)
+                select mistake_id, spotify_id, added_on, 1000 from history
+                where mistake_id = :id;",
+            rusqlite::named_params! { ":id": id(1) },
+        )
+        .unwrap();
+
+        run(&mut conn, &db.0).unwrap();
+
+        assert_eq!(count(&conn, "select count(*) from plays;"), 3);
+        assert_eq!(count(&conn, "select sum(listened_ms) from plays;"), 1000);
+    }
+
+    #[test]
+    fn backs_up_before_migrating() {
+        let db = TempDb::new("backup");
+        let mut conn = baseline(&db);
+
+        run(&mut conn, &db.0).unwrap();
+        assert!(db.backup(0).exists());
+
+        // the backup is the database as it was
+        let backup = rusqlite::Connection::open(db.backup(0)).unwrap();
+        assert_eq!(version(&backup).unwrap(), 0);
+        assert_eq!(count(&backup, "select sum(plays) from history;"), 3);
+    }
+
+    #[test]
+    fn empty_databases_are_not_backed_up() {
+        let db = TempDb::new("empty");
+        let mut conn = db.open();
+
+        run(&mut conn, &db.0).unwrap();
+        assert_eq!(version(&conn).unwrap(), LATEST);
+        assert!(!db.backup(0).exists());
+
+        // there's nothing to do the second time
+        run(&mut conn, &db.0).unwrap();
+        assert!(!db.backup(LATEST).exists());
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let db = TempDb::new("newer");
+        let mut conn = baseline(&db);
+        conn.pragma_update(None, "user_version", LATEST + 1)
+            .unwrap();
+
+        let err = run(&mut conn, &db.0).unwrap_err();
+        assert!(err.to_string().contains("only supports up to"), "{err}");
+
+        // nothing is touched
+        assert_eq!(version(&conn).unwrap(), LATEST + 1);
+        assert!(!db.backup(LATEST + 1).exists());
+        assert_eq!(count(&conn, "select sum(plays) from history;"), 3);
+    }
+}
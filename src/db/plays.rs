@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use super::{Connection, Item};
+
+// this is a play as it's kept in a backup, the track comes from the request
+#[derive(Clone, Debug, ::serde::Serialize, ::serde::Deserialize)]
+pub struct Play {
+    pub mistake_id: uuid::Uuid,
+    pub started_on: time::OffsetDateTime,
+    pub finished_on: Option<time::OffsetDateTime>,
+    pub listened_ms: i64,
+    pub skipped: bool,
+}
+
+impl Connection {
+    // this records that the item started playing, returning the play to finish later
+    pub fn start_play<'a>(&self, item: impl Into<Item<'a>>) -> rusqlite::Result<i64> {
+        let item = item.into();
+        self.conn.execute(
+            "insert into plays (mistake_id, spotify_id, started_on)
+                values (:mistake_id, :spotify_id, :started_on);",
+            rusqlite::named_params! {
+                ":mistake_id": item.id,
+                ":spotify_id": item.spotify_id.to_raw(),
+                ":started_on": time::OffsetDateTime::now_utc(),
+            },
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn finish_play(
+        &self,
+        play: i64,
+        listened: Duration,
+        skipped: bool,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "update plays set
+                finished_on = :finished_on,
+                listened_ms = :listened_ms,
+                skipped = :skipped
+                where rowid = :play and finished_on is null;",
+            rusqlite::named_params! {
+                ":play": play,
+                ":finished_on": time::OffsetDateTime::now_utc(),
+                ":listened_ms": listened.as_millis() as i64,
+                ":skipped": skipped,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn get_plays(&self) -> rusqlite::Result<Vec<Play>> {
+        let mut stmt = self.conn.prepare(
+            "select mistake_id, started_on, finished_on, listened_ms, skipped from plays
+                order by started_on;",
+        )?;
+
+        let mut plays = vec![];
+        for play in stmt.query_map((), |row| {
+            Ok(Play {
+                mistake_id: row.get("mistake_id")?,
+                started_on: row.get("started_on")?,
+                finished_on: row.get("finished_on")?,
+                listened_ms: row.get("listened_ms")?,
+                skipped: row.get("skipped")?,
+            })
+        })? {
+            plays.push(play?);
+        }
+        Ok(plays)
+    }
+
+    // this is false if the play was already recorded, or its request isn't in the history
+    pub fn import_play(&self, play: &Play) -> rusqlite::Result<bool> {
+        let inserted = self.conn.execute(
+            "insert into plays (
+                mistake_id, spotify_id, started_on, finished_on, listened_ms, skipped
+            )
+                select h.mistake_id, h.spotify_id, :started_on, :finished_on, :listened_ms, :skipped
+                from history as h
+                where h.mistake_id = :mistake_id
+                and not exists (
+                    select 1 from plays as p
+                    where p.mistake_id = :mistake_id
+                    and p.started_on = :started_on
+                );",
+            rusqlite::named_params! {
+                ":mistake_id": play.mistake_id,
+                ":started_on": play.started_on,
+                ":finished_on": play.finished_on,
+                ":listened_ms": play.listened_ms,
+                ":skipped": play.skipped,
+            },
+        )?;
+        Ok(inserted > 0)
+    }
+}
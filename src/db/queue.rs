@@ -0,0 +1,257 @@
+use uuid::Uuid;
+
+use super::{Connection, Item};
+
+// every change reads the current order, modifies it and writes it back in a single transaction
+// so the play order is always dense (0..len) and a failed write leaves the old order intact
+impl Connection {
+    // this appends the item to the end of the queue
+    pub fn queue<'a>(&self, item: impl Into<Item<'a>>) -> rusqlite::Result<()> {
+        self.queue_at(item, usize::MAX)
+    }
+
+    // this inserts the item at `position` (clamped to the end), moving it if its already queued
+    pub fn queue_at<'a>(&self, item: impl Into<Item<'a>>, position: usize) -> rusqlite::Result<()> {
+        let item = item.into();
+        let id = item.id;
+
+        let tx = self.conn.unchecked_transaction()?;
+        // the queued row references the history row, so it has to exist first
+        Self::insert_history(&tx, &item)?;
+        Self::rewrite_queue(&tx, |ids| {
+            ids.retain(|&queued| queued != id);
+            ids.insert(position.min(ids.len()), id);
+        })?;
+        tx.commit()
+    }
+
+    pub fn remove_from_queue<'a>(&self, item: impl Into<Item<'a>>) -> rusqlite::Result<bool> {
+        let id = item.into().id;
+
+        let mut removed = false;
+        self.modify_queue(|ids| {
+            let len = ids.len();
+            ids.retain(|&queued| queued != id);
+            removed = ids.len() != len;
+        })?;
+        Ok(removed)
+    }
+
+    // this moves a queued item to `position` (clamped to the end)
+    pub fn move_in_queue(&self, id: Uuid, position: usize) -> rusqlite::Result<bool> {
+        let mut moved = false;
+        self.modify_queue(|ids| {
+            let Some(from) = ids.iter().position(|&queued| queued == id) else { return };
+            let id = ids.remove(from);
+            ids.insert(position.min(ids.len()), id);
+            moved = true;
+        })?;
+        Ok(moved)
+    }
+
+    // this replaces the queue with `ids`, in that order
+    pub fn set_queue_order(&self, ids: impl IntoIterator<Item = Uuid>) -> rusqlite::Result<()> {
+        let ids = ids.into_iter().collect::<Vec<_>>();
+        self.modify_queue(|queued| *queued = ids)
+    }
+
+    // this renumbers the play order without changing it
+    pub fn compact_queue(&self) -> rusqlite::Result<()> {
+        self.modify_queue(|_| {})
+    }
+
+    pub fn get_queued_ids(&self) -> rusqlite::Result<Vec<Uuid>> {
+        Self::read_queue(&self.conn)
+    }
+
+    fn modify_queue(&self, modify: impl FnOnce(&mut Vec<Uuid>)) -> rusqlite::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        Self::rewrite_queue(&tx, modify)?;
+        tx.commit()
+    }
+
+    fn rewrite_queue(
+        conn: &rusqlite::Connection,
+        modify: impl FnOnce(&mut Vec<Uuid>),
+    ) -> rusqlite::Result<()> {
+        let mut ids = Self::read_queue(conn)?;
+        modify(&mut ids);
+        Self::write_queue(conn, &ids)
+    }
+
+    fn read_queue(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<Uuid>> {
+        let mut stmt = conn.prepare(
+            "select queue from queued
+                order by play_order;",
+        )?;
+
+        let mut ids = vec![];
+        for id in stmt.query_map((), |row| row.get("queue"))? {
+            ids.push(id?);
+        }
+        Ok(ids)
+    }
+
+    fn write_queue(conn: &rusqlite::Connection, ids: &[Uuid]) -> rusqlite::Result<()> {
+        conn.execute("delete from queued;", ())?;
+
+        let mut stmt = conn.prepare(
+            "insert into queued(queue, play_order)
+                values(:id, :play_order);",
+        )?;
+        for (play_order, id) in ids.iter().enumerate() {
+            stmt.execute(rusqlite::named_params! {
+                ":id": id,
+                ":play_order": play_order,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::super::{test_util, Connection, Item};
+
+    fn item(n: u8) -> Item<'static> {
+        let added_on = time::OffsetDateTime::UNIX_EPOCH + time::Duration::minutes(n as _);
+        test_util::item(n, test_util::track_id(n), "someone", added_on)
+    }
+
+    fn id(n: u8) -> Uuid {
+        Uuid::from_u128(n as _)
+    }
+
+    // the play order has to be exactly 0..len, in the same order as the ids
+    fn queued(db: &Connection) -> Vec<Uuid> {
+        let ids = db.get_queued_ids().unwrap();
+
+        let mut stmt = db
+            .conn
+            .prepare("select play_order from queued order by play_order;")
+            .unwrap();
+        let order = stmt
+            .query_map((), |row| row.get::<_, usize>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(order, (0..ids.len()).collect::<Vec<_>>());
+
+        ids
+    }
+
+    fn queue_of(items: impl IntoIterator<Item = u8>) -> Connection {
+        let db = test_util::open();
+        for n in items {
+            db.queue(item(n)).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn queue_appends() {
+        let db = queue_of([1, 2, 3]);
+        assert_eq!(queued(&db), [id(1), id(2), id(3)]);
+
+        // the history row is only added once
+        db.queue(item(1)).unwrap();
+        assert_eq!(queued(&db), [id(2), id(3), id(1)]);
+        assert_eq!(db.get_all_history().len(), 3);
+    }
+
+    #[test]
+    fn queue_at_inserts_and_moves() {
+        let db = queue_of([1, 2, 3]);
+
+        db.queue_at(item(4), 0).unwrap();
+        assert_eq!(queued(&db), [id(4), id(1), id(2), id(3)]);
+
+        db.queue_at(item(5), 2).unwrap();
+        assert_eq!(queued(&db), [id(4), id(1), id(5), id(2), id(3)]);
+
+        db.queue_at(item(6), 100).unwrap();
+        assert_eq!(queued(&db), [id(4), id(1), id(5), id(2), id(3), id(6)]);
+
+        // this is already queued, so it's moved instead
+        db.queue_at(item(3), 0).unwrap();
+        assert_eq!(queued(&db), [id(3), id(4), id(1), id(5), id(2), id(6)]);
+    }
+
+    #[test]
+    fn queue_at_is_atomic() {
+        let db = queue_of([1, 2]);
+
+        // this collides with the history row of `1`, so nothing may be queued
+        let mut clashing = item(3);
+        clashing.added_on = item(1).added_on;
+        assert!(db.queue_at(clashing, 0).is_err());
+
+        assert_eq!(queued(&db), [id(1), id(2)]);
+        assert_eq!(db.get_all_history().len(), 2);
+    }
+
+    #[test]
+    fn remove_from_middle_then_insert() {
+        let db = queue_of([1, 2, 3]);
+
+        assert!(db.remove_from_queue(item(2)).unwrap());
+        assert!(!db.remove_from_queue(item(2)).unwrap());
+        assert_eq!(queued(&db), [id(1), id(3)]);
+
+        // the freed play order used to collide with the next insert
+        db.queue(item(4)).unwrap();
+        assert_eq!(queued(&db), [id(1), id(3), id(4)]);
+
+        db.queue_at(item(5), 1).unwrap();
+        assert_eq!(queued(&db), [id(1), id(5), id(3), id(4)]);
+    }
+
+    #[test]
+    fn move_in_queue() {
+        let db = queue_of([1, 2, 3, 4]);
+
+        assert!(db.move_in_queue(id(3), 0).unwrap());
+        assert_eq!(queued(&db), [id(3), id(1), id(2), id(4)]);
+
+        assert!(db.move_in_queue(id(3), usize::MAX).unwrap());
+        assert_eq!(queued(&db), [id(1), id(2), id(4), id(3)]);
+
+        assert!(db.move_in_queue(id(1), 2).unwrap());
+        assert_eq!(queued(&db), [id(2), id(4), id(1), id(3)]);
+
+        assert!(!db.move_in_queue(id(9), 0).unwrap());
+        assert_eq!(queued(&db), [id(2), id(4), id(1), id(3)]);
+    }
+
+    #[test]
+    fn set_queue_order() {
+        let db = queue_of([1, 2, 3]);
+
+        db.set_queue_order([id(3), id(1), id(2)]).unwrap();
+        assert_eq!(queued(&db), [id(3), id(1), id(2)]);
+
+        db.set_queue_order([id(2)]).unwrap();
+        assert_eq!(queued(&db), [id(2)]);
+
+        db.set_queue_order(Vec::new()).unwrap();
+        assert!(queued(&db).is_empty());
+    }
+
+    #[test]
+    fn compact_queue() {
+        let db = queue_of([1, 2, 3]);
+
+        // older versions left gaps behind
+        db.conn
+            .execute_batch(
+                "update queued set play_order = play_order + 100;
+                update queued set play_order = play_order * 3;",
+            )
+            .unwrap();
+
+        db.compact_queue().unwrap();
+        assert_eq!(queued(&db), [id(1), id(2), id(3)]);
+    }
+}
@@ -0,0 +1,552 @@
+use std::{ops::Range, time::Duration};
+
+use librespot::core::SpotifyId;
+
+use super::Connection;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Window {
+    // these count back from now, not from the start of the day or week
+    Day,
+    #[default]
+    Week,
+    Month,
+    Year,
+    All,
+    // whole days in utc, both ends included
+    Custom {
+        from: time::Date,
+        to: time::Date,
+    },
+}
+
+impl Window {
+    pub const ALL: [Self; 5] = [Self::Day, Self::Week, Self::Month, Self::Year, Self::All];
+
+    pub fn range(&self) -> Range<time::OffsetDateTime> {
+        let now = time::OffsetDateTime::now_utc();
+        let since = match self {
+            Self::Day => now - time::Duration::days(1),
+            Self::Week => now - time::Duration::weeks(1),
+            Self::Month => now - time::Duration::days(30),
+            Self::Year => now - time::Duration::days(365),
+            Self::All => time::OffsetDateTime::UNIX_EPOCH,
+            Self::Custom { from, to } => {
+                let until = to.next_day().unwrap_or(*to);
+                return from.midnight().assume_utc()..until.midnight().assume_utc();
+            }
+        };
+        since..now
+    }
+
+    pub const fn label(&self) -> &'static str {
+        match self {
+            Self::Day => "24 hours",
+            Self::Week => "Week",
+            Self::Month => "Month",
+            Self::Year => "Year",
+            Self::All => "All time",
+            Self::Custom { .. } => "Custom",
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Day => "in the last 24 hours".to_string(),
+            Self::Week => "in the last week".to_string(),
+            Self::Month => "in the last 30 days".to_string(),
+            Self::Year => "in the last year".to_string(),
+            Self::All => "of all time".to_string(),
+            Self::Custom { from, to } if from == to => format!("on {from}"),
+            Self::Custom { from, to } => format!("from {from} to {to}"),
+        }
+    }
+}
+
+// a track, an artist or a chatter and how often they came up
+#[derive(Clone, Debug)]
+pub struct Ranked {
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UserStats {
+    pub requests: usize,
+    pub favourite_artist: Option<Ranked>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChannelStats {
+    pub played: usize,
+    pub listened: Duration,
+    pub requests: usize,
+    pub requesters: usize,
+}
+
+// deleted history is left out of everything here
+impl Connection {
+    pub fn top_tracks(
+        &self,
+        range: &Range<time::OffsetDateTime>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<Ranked>> {
+        let mut stmt = self.conn.prepare(
+            "select t.name as name, (
+                    select ta.name from track_artists as ta
+                    where ta.spotify_id = h.spotify_id
+                    order by ta.position
+                    limit 1
+                ) as artist, count(*) as count
+                from history as h
+                join tracks as t on t.spotify_id = h.spotify_id
+                where h.added_on >= :since and h.added_on < :until and h.deleted = false
+                group by h.spotify_id
+                order by count desc, max(h.added_on) desc
+                limit :limit;",
+        )?;
+
+        let rows = stmt.query_map(
+            rusqlite::named_params! {
+                ":since": range.start,
+                ":until": range.end,
+                ":limit": limit,
+            },
+            |row| {
+                let name: String = row.get("name")?;
+                let artist: Option<String> = row.get("artist")?;
+                Ok(Ranked {
+                    name: match artist {
+                        Some(artist) => format!("{name} by {artist}"),
+                        None => name,
+                    },
+                    count: row.get("count")?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    pub fn top_artists(
+        &self,
+        range: &Range<time::OffsetDateTime>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<Ranked>> {
+        self.ranked_artists(
+            "where h.added_on >= :since and h.added_on < :until and h.deleted = false",
+            rusqlite::named_params! {
+                ":since": range.start,
+                ":until": range.end,
+                ":limit": limit,
+            },
+        )
+    }
+
+    pub fn top_requesters(
+        &self,
+        range: &Range<time::OffsetDateTime>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<Ranked>> {
+        // names can change, so the latest one is used
+        let mut stmt = self.conn.prepare(
+            "select (
+                    select l.sender_name from history as l
+                    where l.sender_id = h.sender_id
+                    order by l.added_on desc
+                    limit 1
+                ) as name, count(*) as count
+                from history as h
+                where h.added_on >= :since and h.added_on < :until and h.deleted = false
+                group by h.sender_id
+                order by count desc, max(h.added_on) desc
+                limit :limit;",
+        )?;
+
+        let rows = stmt.query_map(
+            rusqlite::named_params! {
+                ":since": range.start,
+                ":until": range.end,
+                ":limit": limit,
+            },
+            |row| {
+                Ok(Ranked {
+                    name: row.get("name")?,
+                    count: row.get("count")?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    // days are `YYYY-MM-DD` in utc, and only days with requests are included
+    pub fn requests_per_day(
+        &self,
+        range: &Range<time::OffsetDateTime>,
+    ) -> rusqlite::Result<Vec<(String, usize)>> {
+        let mut stmt = self.conn.prepare(
+            "select date(added_on) as day, count(*) as count
+                from history
+                where added_on >= :since and added_on < :until and deleted = false
+                group by day
+                order by day;",
+        )?;
+
+        let rows = stmt.query_map(
+            rusqlite::named_params! {":since": range.start, ":until": range.end},
+            |row| Ok((row.get("day")?, row.get("count")?)),
+        )?;
+        rows.collect()
+    }
+
+    // this is indexed by the hour of the day, in utc
+    pub fn requests_per_hour(
+        &self,
+        range: &Range<time::OffsetDateTime>,
+    ) -> rusqlite::Result<[usize; 24]> {
+        let mut stmt = self.conn.prepare(
+            "select cast(strftime('%H', added_on) as integer) as hour, count(*) as count
+                from history
+                where added_on >= :since and added_on < :until and deleted = false
+                group by hour;",
+        )?;
+
+        let mut hours = [0; 24];
+        let rows = stmt.query_map(
+            rusqlite::named_params! {":since": range.start, ":until": range.end},
+            |row| Ok((row.get::<_, usize>("hour")?, row.get("count")?)),
+        )?;
+        for row in rows {
+            let (hour, count) = row?;
+            if let Some(slot) = hours.get_mut(hour) {
+                *slot = count;
+            }
+        }
+        Ok(hours)
+    }
+
+    pub fn listening_time(
+        &self,
+        range: &Range<time::OffsetDateTime>,
+    ) -> rusqlite::Result<Duration> {
+        let listened = self.conn.query_row(
+            "select coalesce(sum(listened_ms), 0) from plays
+                where started_on >= :since and started_on < :until;",
+            rusqlite::named_params! {":since": range.start, ":until": range.end},
+            |row| row.get::<_, i64>(0),
+        )?;
+        Ok(Duration::from_millis(listened.max(0) as _))
+    }
+
+    pub fn user_stats(&self, sender_id: &str) -> rusqlite::Result<UserStats> {
+        let requests = self.conn.query_row(
+            "select count(*) from history
+                where sender_id = :sender_id and deleted = false;",
+            rusqlite::named_params! {":sender_id": sender_id},
+            |row| row.get(0),
+        )?;
+
+        let favourite_artist = self
+            .ranked_artists(
+                "where h.sender_id = :sender_id and h.deleted = false",
+                rusqlite::named_params! {":sender_id": sender_id, ":limit": 1},
+            )?
+            .pop();
+
+        Ok(UserStats {
+            requests,
+            favourite_artist,
+        })
+    }
+
+    pub fn channel_stats(&self) -> rusqlite::Result<ChannelStats> {
+        let (played, listened) = self.conn.query_row(
+            "select count(*), coalesce(sum(listened_ms), 0) from plays;",
+            (),
+            |row| Ok((row.get(0)?, row.get::<_, i64>(1)?)),
+        )?;
+
+        let (requests, requesters) = self.conn.query_row(
+            "select count(*), count(distinct sender_id) from history
+                where deleted = false;",
+            (),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        Ok(ChannelStats {
+            played,
+            listened: Duration::from_millis(listened.max(0) as _),
+            requests,
+            requesters,
+        })
+    }
+
+    pub fn play_count(&self, spotify_id: SpotifyId) -> rusqlite::Result<usize> {
+        self.conn.query_row(
+            "select count(*) from plays where spotify_id = :spotify_id;",
+            rusqlite::named_params! {":spotify_id": spotify_id.to_raw()},
+            |row| row.get(0),
+        )
+    }
+
+    // `filter` has to bind everything in `params` except `:limit`
+    fn ranked_artists(
+        &self,
+        filter: &str,
+        params: &[(&str, &dyn rusqlite::ToSql)],
+    ) -> rusqlite::Result<Vec<Ranked>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "select ta.name as name, count(*) as count
+                from history as h
+                join track_artists as ta on ta.spotify_id = h.spotify_id
+                {filter}
+                group by ta.artist_id
+                order by count desc, max(h.added_on) desc
+                limit :limit;"
+        ))?;
+
+        let rows = stmt.query_map(params, |row| {
+            Ok(Ranked {
+                name: row.get("name")?,
+                count: row.get("count")?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use librespot::core::{spotify_id::SpotifyItemType, SpotifyId};
+
+    use super::{
+        super::{test_util, Connection},
+        Window,
+    };
+    use crate::request::{Artist, Track, TrackInfo};
+
+    const ALPHA: u8 = 101;
+    const BETA: u8 = 102;
+
+    fn artist(n: u8) -> Artist {
+        Artist {
+            id: SpotifyId {
+                item_type: SpotifyItemType::Artist,
+                ..SpotifyId::from_raw(&[n; 16]).unwrap()
+            },
+            name: match n {
+                ALPHA => "Alpha",
+                _ => "Beta",
+            }
+            .to_string(),
+        }
+    }
+
+    fn track(db: &Connection, n: u8, name: &str, artists: &[u8]) {
+        let info = TrackInfo {
+            track: Arc::new(Track {
+                id: test_util::track_id(n),
+                name: name.to_string(),
+                artists: artists.iter().copied().map(artist).collect(),
+                duration: 180_000,
+            }),
+            image_id: None,
+            lyrics: Default::default(),
+        };
+        db.cache_track(&info).unwrap();
+    }
+
+    // three tracks spread over two artists, requested by three people. the last two are deleted
+    fn seeded() -> Connection {
+        let db = test_util::open();
+        track(&db, 1, "One", &[ALPHA]);
+        track(&db, 2, "Two", &[ALPHA, BETA]);
+        track(&db, 3, "Three", &[BETA]);
+
+        let now = time::OffsetDateTime::now_utc();
+        let ago = |minutes: i64| now - time::Duration::minutes(minutes);
+        let history = [
+            (1, 1, "alice", ago(60)),
+            (2, 1, "bob", ago(2 * 60)),
+            (3, 2, "alice", ago(3 * 60)),
+            (4, 3, "bob", ago(3 * 24 * 60)),
+            (5, 3, "carol", ago(60 * 24 * 60)),
+            (6, 3, "carol", ago(61 * 24 * 60)),
+            (7, 3, "alice", ago(150)),
+        ];
+        for (n, track, sender, added_on) in history {
+            let item = test_util::item(n, test_util::track_id(track), sender, added_on);
+            assert!(db.add_history(item).unwrap());
+        }
+        for n in [6, 7] {
+            let item = test_util::item(n, test_util::track_id(3), "", now);
+            assert!(db.remove_from_history(item));
+        }
+
+        let item = |n, track, sender| test_util::item(n, test_util::track_id(track), sender, now);
+        let play = db.start_play(item(1, 1, "alice")).unwrap();
+        db.finish_play(play, Duration::from_secs(60), false)
+            .unwrap();
+        let play = db.start_play(item(2, 1, "bob")).unwrap();
+        db.finish_play(play, Duration::from_secs(30), true).unwrap();
+
+        db
+    }
+
+    fn ranked(rows: Vec<super::Ranked>) -> Vec<(String, usize)> {
+        rows.into_iter().map(|r| (r.name, r.count)).collect()
+    }
+
+    fn owned<'a>(expected: impl IntoIterator<Item = (&'a str, usize)>) -> Vec<(String, usize)> {
+        expected
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .collect()
+    }
+
+    #[test]
+    fn windows_end_now() {
+        let now = time::OffsetDateTime::now_utc();
+        for window in Window::ALL {
+            let range = window.range();
+            assert!(range.start < range.end);
+            assert!(range.end >= now);
+            assert!(range.end - now < time::Duration::minutes(1));
+        }
+
+        let day = Window::Day.range();
+        assert!(day.contains(&(now - time::Duration::hours(23))));
+        assert!(!day.contains(&(now - time::Duration::hours(25))));
+        assert_eq!(Window::All.range().start, time::OffsetDateTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn custom_windows_cover_whole_days() {
+        let date = |day| time::Date::from_calendar_date(2023, time::Month::June, day).unwrap();
+        let range = Window::Custom {
+            from: date(1),
+            to: date(2),
+        }
+        .range();
+        assert_eq!(range.start, date(1).midnight().assume_utc());
+        assert_eq!(range.end, date(3).midnight().assume_utc());
+
+        let db = seeded();
+        let three_days_ago = (time::OffsetDateTime::now_utc() - time::Duration::days(3)).date();
+        let window = Window::Custom {
+            from: three_days_ago,
+            to: three_days_ago,
+        };
+        let tracks = db.top_tracks(&window.range(), 10).unwrap();
+        assert_eq!(ranked(tracks), owned([("Three by Beta", 1)]));
+    }
+
+    #[test]
+    fn top_tracks_in_window() {
+        let db = seeded();
+
+        // ties go to whatever was requested last
+        let day = db.top_tracks(&Window::Day.range(), 10).unwrap();
+        assert_eq!(
+            ranked(day),
+            owned([("One by Alpha", 2), ("Two by Alpha", 1)])
+        );
+
+        let week = db.top_tracks(&Window::Week.range(), 10).unwrap();
+        assert_eq!(
+            ranked(week),
+            owned([
+                ("One by Alpha", 2),
+                ("Two by Alpha", 1),
+                ("Three by Beta", 1)
+            ])
+        );
+
+        let all = db.top_tracks(&Window::All.range(), 10).unwrap();
+        assert_eq!(
+            ranked(all),
+            owned([
+                ("One by Alpha", 2),
+                ("Three by Beta", 2),
+                ("Two by Alpha", 1)
+            ])
+        );
+
+        let limited = db.top_tracks(&Window::All.range(), 1).unwrap();
+        assert_eq!(ranked(limited), owned([("One by Alpha", 2)]));
+    }
+
+    #[test]
+    fn top_artists_count_every_artist_of_a_track() {
+        let db = seeded();
+
+        let week = db.top_artists(&Window::Week.range(), 10).unwrap();
+        assert_eq!(ranked(week), owned([("Alpha", 3), ("Beta", 2)]));
+
+        let all = db.top_artists(&Window::All.range(), 10).unwrap();
+        assert_eq!(ranked(all), owned([("Alpha", 3), ("Beta", 3)]));
+    }
+
+    #[test]
+    fn top_requesters_in_window() {
+        let db = seeded();
+
+        let week = db.top_requesters(&Window::Week.range(), 10).unwrap();
+        assert_eq!(ranked(week), owned([("alice", 2), ("bob", 2)]));
+
+        let all = db.top_requesters(&Window::All.range(), 10).unwrap();
+        assert_eq!(ranked(all), owned([("alice", 2), ("bob", 2), ("carol", 1)]));
+    }
+
+    #[test]
+    fn requests_per_hour_and_day_leave_out_deleted() {
+        let db = seeded();
+
+        let hours = db.requests_per_hour(&Window::Week.range()).unwrap();
+        assert_eq!(hours.iter().sum::<usize>(), 4);
+
+        let days = db.requests_per_day(&Window::All.range()).unwrap();
+        assert_eq!(days.iter().map(|(_, count)| count).sum::<usize>(), 5);
+    }
+
+    #[test]
+    fn user_stats_with_favourite_artist() {
+        let db = seeded();
+
+        let alice = db.user_stats("alice-id").unwrap();
+        assert_eq!(alice.requests, 2);
+        let favourite = alice.favourite_artist.unwrap();
+        assert_eq!((favourite.name.as_str(), favourite.count), ("Alpha", 2));
+
+        let carol = db.user_stats("carol-id").unwrap();
+        assert_eq!(carol.requests, 1);
+        let favourite = carol.favourite_artist.unwrap();
+        assert_eq!((favourite.name.as_str(), favourite.count), ("Beta", 1));
+
+        let nobody = db.user_stats("nobody-id").unwrap();
+        assert_eq!(nobody.requests, 0);
+        assert!(nobody.favourite_artist.is_none());
+    }
+
+    #[test]
+    fn channel_stats_and_listening_time() {
+        let db = seeded();
+
+        let stats = db.channel_stats().unwrap();
+        assert_eq!(stats.played, 2);
+        assert_eq!(stats.listened, Duration::from_secs(90));
+        assert_eq!(stats.requests, 5);
+        assert_eq!(stats.requesters, 3);
+
+        let day = db.listening_time(&Window::Day.range()).unwrap();
+        assert_eq!(day, Duration::from_secs(90));
+
+        let now = time::OffsetDateTime::now_utc();
+        let before = now - time::Duration::days(2)..now - time::Duration::days(1);
+        assert_eq!(db.listening_time(&before).unwrap(), Duration::ZERO);
+
+        assert_eq!(db.play_count(test_util::track_id(1)).unwrap(), 2);
+        assert_eq!(db.play_count(test_util::track_id(3)).unwrap(), 0);
+    }
+}
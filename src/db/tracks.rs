@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use librespot::core::{FileId, SpotifyId};
+
+use super::Connection;
+use crate::{
+    request::{Artist, Track, TrackInfo},
+    spotify_lyrics::{LyricLine, SpotifyLyrics},
+};
+
+pub struct CachedTrack {
+    pub info: TrackInfo,
+    pub updated_on: time::OffsetDateTime,
+}
+
+impl Connection {
+    // this replaces any cached metadata for the track
+    pub fn cache_track(&self, info: &TrackInfo) -> anyhow::Result<()> {
+        let TrackInfo {
+            track,
+            image_id,
+            lyrics,
+        } = info;
+
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "insert into tracks (spotify_id, name, duration, cover, lyrics, synced, updated_on)
+                values (:spotify_id, :name, :duration, :cover, :lyrics, :synced, :updated_on)
+                on conflict(spotify_id) do update set
+                    name = excluded.name,
+                    duration = excluded.duration,
+                    cover = excluded.cover,
+                    lyrics = excluded.lyrics,
+                    synced = excluded.synced,
+                    updated_on = excluded.updated_on;",
+            rusqlite::named_params! {
+                ":spotify_id": track.id.to_raw(),
+                ":name": track.name,
+                ":duration": track.duration,
+                ":cover": image_id.map(|id| id.0),
+                ":lyrics": serde_json::to_string(&*lyrics.lyrics)?,
+                ":synced": lyrics.synced,
+                ":updated_on": time::OffsetDateTime::now_utc(),
+            },
+        )?;
+
+        tx.execute(
+            "delete from track_artists where spotify_id = :spotify_id;",
+            rusqlite::named_params! {":spotify_id": track.id.to_raw()},
+        )?;
+
+        for (position, artist) in track.artists.iter().enumerate() {
+            // artists are stored as uris so they keep their item type
+            let Ok(uri) = artist.id.to_uri() else { continue };
+            tx.execute(
+                "insert into track_artists (spotify_id, position, artist_id, name)
+                    values (:spotify_id, :position, :artist_id, :name);",
+                rusqlite::named_params! {
+                    ":spotify_id": track.id.to_raw(),
+                    ":position": position,
+                    ":artist_id": uri,
+                    ":name": artist.name,
+                },
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn cached_track(&self, id: SpotifyId) -> anyhow::Result<Option<CachedTrack>> {
+        let mut stmt = self
+            .conn
+            .prepare("select * from tracks where spotify_id = :spotify_id;")?;
+        let mut rows = stmt.query(rusqlite::named_params! {":spotify_id": id.to_raw()})?;
+        let Some(row) = rows.next()? else { return Ok(None) };
+
+        let name: String = row.get("name")?;
+        let duration: i32 = row.get("duration")?;
+        let cover: Option<[u8; 20]> = row.get("cover")?;
+        let lines: Vec<LyricLine> = serde_json::from_str(&row.get::<_, String>("lyrics")?)?;
+        let synced: bool = row.get("synced")?;
+        let updated_on = row.get("updated_on")?;
+
+        let mut stmt = self.conn.prepare(
+            "select artist_id, name from track_artists
+                where spotify_id = :spotify_id
+                order by position;",
+        )?;
+        let rows = stmt.query_map(
+            rusqlite::named_params! {":spotify_id": id.to_raw()},
+            |row| Ok((row.get::<_, String>("artist_id")?, row.get("name")?)),
+        )?;
+
+        let mut artists = vec![];
+        for row in rows {
+            let (uri, name) = row?;
+            let Ok(id) = SpotifyId::from_uri(&uri) else { continue };
+            artists.push(Artist { id, name });
+        }
+
+        Ok(Some(CachedTrack {
+            info: TrackInfo {
+                track: Arc::new(Track {
+                    id,
+                    name,
+                    artists,
+                    duration,
+                }),
+                image_id: cover.map(FileId),
+                lyrics: SpotifyLyrics {
+                    lyrics: Arc::from(lines),
+                    synced,
+                },
+            },
+            updated_on,
+        }))
+    }
+}
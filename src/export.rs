@@ -0,0 +1,216 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use time::format_description::well_known::Rfc3339;
+
+use crate::{db, ext::JoinWith as _, history::HistoryItem};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    M3u,
+}
+
+impl Format {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::M3u => "m3u",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "json" => Self::Json,
+            "csv" => Self::Csv,
+            "m3u" => Self::M3u,
+            _ => anyhow::bail!("unknown format `{s}`, expected json, csv or m3u"),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Summary {
+    pub history: usize,
+    pub queue: usize,
+    pub plays: usize,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{history} history and {queue} queued entries",
+            history = self.history,
+            queue = self.queue
+        )?;
+        // only json has plays in it
+        if self.plays > 0 {
+            write!(f, " with {plays} plays", plays = self.plays)?;
+        }
+        Ok(())
+    }
+}
+
+// only the json form can be imported again, the others are for people.
+// track metadata isn't kept, it's looked up again when the history is loaded
+#[derive(::serde::Serialize, ::serde::Deserialize)]
+struct Backup<'a> {
+    history: Vec<HistoryItem<'a>>,
+    queue: Vec<HistoryItem<'a>>,
+    // older backups don't have these
+    #[serde(default)]
+    plays: Vec<db::Play>,
+}
+
+pub fn export_to(db: &db::Connection, format: Format, path: &Path) -> anyhow::Result<Summary> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let summary = export(db, format, &mut out)?;
+    out.flush()?;
+    Ok(summary)
+}
+
+pub fn export(
+    db: &db::Connection,
+    format: Format,
+    out: &mut impl Write,
+) -> anyhow::Result<Summary> {
+    let history = db.get_all_history();
+    let queue = db.get_queued();
+    let mut summary = Summary {
+        history: history.len(),
+        queue: queue.len(),
+        plays: 0,
+    };
+
+    match format {
+        Format::Json => {
+            let backup = Backup {
+                history: history.into_iter().map(HistoryItem::from).collect(),
+                queue: queue.into_iter().map(HistoryItem::from).collect(),
+                plays: db.get_plays()?,
+            };
+            summary.plays = backup.plays.len();
+            serde_json::to_writer_pretty(out, &backup)?;
+        }
+        Format::Csv => {
+            writeln!(out, "list,title,artists,requester,added_on,plays")?;
+            for (list, item) in tagged(queue, history) {
+                let track = db.cached_track(item.spotify_id)?.map(|c| c.info.track);
+                writeln!(
+                    out,
+                    "{list},{title},{artists},{requester},{added_on},{plays}",
+                    title = csv_field(track.as_ref().map_or("", |t| &t.name)),
+                    artists = csv_field(
+                        &track
+                            .as_ref()
+                            .map(|t| t.artists.iter().map(|c| &c.name).join(", "))
+                            .unwrap_or_default()
+                    ),
+                    requester = csv_field(&item.sender_name),
+                    added_on = item.added_on.format(&Rfc3339)?,
+                    plays = item.plays,
+                )?;
+            }
+        }
+        Format::M3u => {
+            writeln!(out, "#EXTM3U")?;
+            for (list, item) in tagged(queue, history) {
+                let Ok(uri) = item.spotify_id.to_uri() else { continue };
+                if let Some(cached) = db.cached_track(item.spotify_id)? {
+                    let track = cached.info.track;
+                    writeln!(
+                        out,
+                        "#EXTINF:{secs},{artists} - {title} ({list})",
+                        secs = track.duration / 1000,
+                        artists = track.artists.iter().map(|c| &c.name).join(", "),
+                        title = track.name,
+                    )?;
+                }
+                writeln!(out, "{uri}")?;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Import {
+    Everything,
+    // the gui keeps its own queue and writes it back over the stored one, so it can't take these
+    WithoutQueue,
+}
+
+// this merges a json export into the database, anything that already exists is kept as-is
+pub fn import_from(db: &db::Connection, path: &Path, import: Import) -> anyhow::Result<Summary> {
+    let backup: Backup<'static> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+    let mut summary = Summary::default();
+    for item in &backup.history {
+        if skip_conflict(db.add_history(item))?.unwrap_or_default() {
+            summary.history += 1;
+        }
+    }
+
+    let queue = match import {
+        Import::Everything => &*backup.queue,
+        Import::WithoutQueue => &[],
+    };
+    let queued = db.get_queued_ids()?;
+    for item in queue {
+        if queued.contains(&item.id) {
+            continue;
+        }
+        if skip_conflict(db.queue(item))?.is_some() {
+            summary.queue += 1;
+        }
+    }
+
+    for play in &backup.plays {
+        if db.import_play(play)? {
+            summary.plays += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+// a different request made at the exact same time is most likely the same one, so it's kept
+fn skip_conflict<T>(result: rusqlite::Result<T>) -> rusqlite::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::SqliteFailure(err, _))
+            if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn tagged(
+    queue: Vec<db::Item<'static>>,
+    history: Vec<db::Item<'static>>,
+) -> impl Iterator<Item = (&'static str, db::Item<'static>)> {
+    let queue = queue.into_iter().map(|item| ("queue", item));
+    let history = history.into_iter().map(|item| ("history", item));
+    queue.chain(history)
+}
+
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if !field.contains([',', '"', '\n', '\r']) {
+        return Cow::Borrowed(field);
+    }
+    Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+}
@@ -1,63 +1,185 @@
-use std::{borrow::Cow, collections::BTreeMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use librespot::{
-    core::{Session, SpotifyId},
-    metadata::{image::ImageSize, Lyrics, Metadata as _, Track},
+use hashbrown::HashSet;
+use librespot::core::{Session, SpotifyId};
+use tokio::{
+    sync::{mpsc::UnboundedSender, Semaphore},
+    task::JoinSet,
 };
-use tokio::{sync::mpsc::UnboundedSender, task::JoinSet};
 
 use crate::{
-    async_adapter::Fut, bot::SynthEvent, db, spotify_lyrics::SpotifyLyrics, twitch, Request,
+    async_adapter::Fut, bot::SynthEvent, db, request::TrackInfo, twitch, util::env_or, Request,
 };
 
+// cached metadata older than this is refreshed in the background once everything is loaded
+const CACHE_TTL: time::Duration = time::Duration::days(7);
+
 #[derive(Default)]
 pub struct History {
     pub requests: Vec<Request>,
 }
 
+// this is updated while loading so the ui has something to show
+#[derive(Default)]
+pub struct LoadProgress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl LoadProgress {
+    pub fn get(&self) -> (usize, usize) {
+        (
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// this is shared by every lookup made while loading
+#[derive(Clone)]
+pub struct Lookup {
+    permits: Arc<Semaphore>,
+    retries: usize,
+    progress: Arc<LoadProgress>,
+}
+
+impl Lookup {
+    pub fn from_env(progress: Arc<LoadProgress>) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(env_or("LOOKUP_CONCURRENCY", 8usize).max(1))),
+            retries: env_or("LOOKUP_RETRIES", 3),
+            progress,
+        }
+    }
+
+    fn with_progress(self, progress: Arc<LoadProgress>) -> Self {
+        Self { progress, ..self }
+    }
+}
+
 impl History {
     pub fn load(
         session: &Session,
         db: &db::Connection,
         replay: UnboundedSender<SynthEvent<Request>>,
+        lookup: Lookup,
     ) -> Fut<Self> {
-        use twitch_message::messages::types::{Nickname, UserId};
+        let now = time::OffsetDateTime::now_utc();
+        let (mut missing, mut stale) = (HashSet::new(), HashSet::new());
+        let mut with_cache = |item: HistoryItem<'static>| {
+            let cached = db.cached_track(item.spotify_id).unwrap_or_else(|err| {
+                log::warn!("cannot read cached track {:?}: {err}", item.spotify_id);
+                None
+            });
 
-        let map_db_item = |item: db::Item<'static>| HistoryItem {
-            id: item.id,
-            spotify_id: item.spotify_id,
-            user: Cow::Owned(twitch::User {
-                id: UserId::from(item.sender_id.to_string()),
-                name: Nickname::from(item.sender_name.to_string()),
-                color: db::Item::color_from_u32(item.sender_color),
-            }),
-            added_on: item.added_on,
+            match &cached {
+                None => missing.insert(item.spotify_id),
+                Some(cached) if cached.updated_on + CACHE_TTL < now => {
+                    stale.insert(item.spotify_id)
+                }
+                Some(..) => false,
+            };
+            (item, cached.map(|cached| cached.info))
         };
 
-        let history_items = db.get_all_history().into_iter().map(map_db_item);
-        let queue_items = db.get_queued().into_iter().map(map_db_item);
+        let history_items = db
+            .get_all_history()
+            .into_iter()
+            .map(HistoryItem::from)
+            .map(&mut with_cache)
+            .collect::<Vec<_>>();
+        let queue_items = db
+            .get_queued()
+            .into_iter()
+            .map(HistoryItem::from)
+            .map(&mut with_cache)
+            .collect::<Vec<_>>();
+
+        let path = db.path().map(PathBuf::from);
 
         Fut::spawn({
             let session = session.clone();
             async move {
-                let history = lookup_all_requests(history_items, session.clone(), |requests| {
-                    Self { requests }
-                });
-                let queue = lookup_all_requests(queue_items, session, move |items| {
-                    items
-                        .into_iter()
-                        .map(SynthEvent::Synthetic)
-                        .try_for_each(|item| replay.send(item))
-                        .ok()
-                        .expect("control state incontinuity");
-                });
+                let history = lookup_all_requests(
+                    history_items,
+                    session.clone(),
+                    lookup.clone(),
+                    |requests| Self { requests },
+                );
+                // queued requests are part of the history too, so they'd be counted twice
+                let queue = lookup_all_requests(
+                    queue_items,
+                    session.clone(),
+                    lookup.clone().with_progress(Arc::default()),
+                    move |items| {
+                        items
+                            .into_iter()
+                            .map(SynthEvent::Synthetic)
+                            .try_for_each(|item| replay.send(item))
+                            .ok()
+                            .expect("control state incontinuity");
+                    },
+                );
                 let (history, _) = tokio::join!(history, queue);
-                history.expect("load history")
+                let history = history.expect("load history");
+
+                if let Some(path) = path {
+                    // anything that was missing has just been looked up, so it only has to be stored
+                    let fetched = history
+                        .requests
+                        .iter()
+                        .filter(|req| missing.remove(&req.track.id))
+                        .map(Request::info)
+                        .collect();
+                    tokio::spawn(refresh_cache(session, path, lookup, fetched, stale));
+                }
+
+                history
             }
         })
     }
 }
 
+// this stores freshly looked up metadata and refreshes stale entries without holding up the ui
+async fn refresh_cache(
+    session: Session,
+    path: PathBuf,
+    lookup: Lookup,
+    fetched: Vec<TrackInfo>,
+    stale: HashSet<SpotifyId>,
+) {
+    let db = match db::Connection::open(&path) {
+        Ok(db) => db,
+        Err(err) => {
+            log::warn!("cannot open {} to cache tracks: {err}", path.display());
+            return;
+        }
+    };
+
+    for info in &fetched {
+        if let Err(err) = db.cache_track(info) {
+            log::warn!("cannot cache track {:?}: {err}", info.track.id);
+        }
+    }
+
+    for id in stale {
+        let Some(info) = TrackInfo::fetch_with_retry(&session, id, lookup.retries).await else {
+            continue;
+        };
+        if let Err(err) = db.cache_track(&info) {
+            log::warn!("cannot cache track {id:?}: {err}");
+        }
+    }
+}
+
 #[derive(::serde::Serialize, ::serde::Deserialize)]
 pub struct HistoryItem<'a> {
     pub id: uuid::Uuid,
@@ -68,34 +190,26 @@ pub struct HistoryItem<'a> {
 }
 
 impl HistoryItem<'static> {
-    pub async fn lookup(self, session: &Session) -> Option<Request> {
-        let track = Track::get(session, &self.spotify_id)
-            .await
-            .map(Arc::new)
-            .ok()?;
-        let request = Request {
+    pub fn with_info(self, info: TrackInfo) -> Request {
+        Request {
             id: self.id,
             added_on: self.added_on,
-            image_id: track
-                .album
-                .covers
-                .iter()
-                .find_map(|c| (c.size == ImageSize::DEFAULT).then_some(c.id)),
             user: self.user.into_owned(),
-            lyrics: Lyrics::get(session, &self.spotify_id)
-                .await
-                .ok()
-                .map(|lyrics| SpotifyLyrics::fix_up(lyrics, track.duration as _))
-                .unwrap_or_default(),
-            track,
-        };
-        Some(request)
+            track: info.track,
+            image_id: info.image_id,
+            lyrics: info.lyrics,
+        }
     }
 }
 
+// cached items are used as-is, everything else is looked up a few at a time
 pub fn lookup_all_requests<T>(
-    iterator: impl IntoIterator<Item = HistoryItem<'static>> + Send + Sync + 'static,
+    iterator: impl IntoIterator<Item = (HistoryItem<'static>, Option<TrackInfo>)>
+        + Send
+        + Sync
+        + 'static,
     session: Session,
+    lookup: Lookup,
     map: impl FnOnce(Vec<Request>) -> T + Send + Sync + 'static,
 ) -> tokio::sync::oneshot::Receiver<T>
 where
@@ -105,20 +219,40 @@ where
 
     tokio::spawn({
         async move {
+            let mut tree = BTreeMap::default();
             let mut set = JoinSet::new();
-            // TODO limit this with a semaphore
-            for (id, item) in iterator.into_iter().enumerate() {
+            let Lookup {
+                permits,
+                retries,
+                progress,
+            } = lookup;
+
+            for (id, (item, cached)) in iterator.into_iter().enumerate() {
+                progress.total.fetch_add(1, Ordering::Relaxed);
+                if let Some(info) = cached {
+                    tree.insert(id, Some(item.with_info(info)));
+                    progress.done.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+
                 let session = session.clone();
+                let permits = Arc::clone(&permits);
                 let fut = async move {
-                    let request = item.lookup(&session).await;
+                    let _permit = permits
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore is never closed");
+                    let request = TrackInfo::fetch_with_retry(&session, item.spotify_id, retries)
+                        .await
+                        .map(|info| item.with_info(info));
                     (id, request)
                 };
                 set.spawn(fut);
             }
 
-            let mut tree = BTreeMap::default();
             while let Some(Ok((id, req))) = set.join_next().await {
                 tree.insert(id, req);
+                progress.done.fetch_add(1, Ordering::Relaxed);
             }
 
             let deque = tree.into_values().flatten().collect();
@@ -140,6 +274,23 @@ impl<'a> HistoryItem<'a> {
     }
 }
 
+impl From<db::Item<'static>> for HistoryItem<'static> {
+    fn from(item: db::Item<'static>) -> Self {
+        use twitch_message::messages::types::{Nickname, UserId};
+
+        Self {
+            id: item.id,
+            spotify_id: item.spotify_id,
+            user: Cow::Owned(twitch::User {
+                id: UserId::from(item.sender_id.into_owned()),
+                name: Nickname::from(item.sender_name.into_owned()),
+                color: db::Item::color_from_u32(item.sender_color),
+            }),
+            added_on: item.added_on,
+        }
+    }
+}
+
 mod serde {
     pub mod spotify_id {
         use librespot::core::SpotifyId;
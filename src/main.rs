@@ -1,5 +1,5 @@
 #![cfg_attr(debug_assertions, allow(dead_code, unused_variables,))]
-use std::sync::Arc;
+use std::{path::Path, sync::Arc, time::Duration};
 
 use egui::mutex::Mutex;
 
@@ -17,6 +17,7 @@ use tokio::sync::mpsc::{self, unbounded_channel};
 mod async_adapter;
 mod bot;
 mod control;
+mod export;
 mod ext;
 mod history;
 mod image_cache;
@@ -42,17 +43,36 @@ async fn main() -> anyhow::Result<()> {
     simple_env_load::load_env_from([".dev.env", ".secrets.env"]);
     alto_logger::init_term_logger().expect("init logger");
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if matches!(args.first().map(String::as_str), Some("export" | "import")) {
+        return run_command(&args);
+    }
+
     fn get(key: &str) -> anyhow::Result<String> {
         std::env::var(key).map_err(|_| anyhow::anyhow!("`{key}` must be set"))
     }
 
+    let tls = util::env_or("TWITCH_TLS", false);
+    let default_address = if tls {
+        twitch::TWITCH_IRC_ADDRESS_TLS
+    } else {
+        twitch_message::TWITCH_IRC_ADDRESS
+    };
+
     let config = twitch::Config {
         name: get("TWITCH_NAME")?,
         pass: get("TWITCH_PASS")?,
         main_channel: get("TWITCH_MAIN_CHANNEL")?,
         spam_channel: get("TWITCH_SPAM_CHANNEL")?,
+        address: util::env_or("TWITCH_ADDRESS", default_address.to_string()),
+        tls,
+        ping_timeout: Duration::from_secs(60),
     };
 
+    // both sides get their own connection, this one runs any pending migrations
+    let bot_db = db::Connection::open(db::DEFAULT_PATH)?;
+    let control_db = db::Connection::open(db::DEFAULT_PATH)?;
+
     let spotify_api_client = rspotify::ClientCredsSpotify::with_config(
         rspotify::Credentials::new(&get("SPOTIFY_CLIENT_ID")?, &get("SPOTIFY_CLIENT_SECRET")?),
         rspotify::Config {
@@ -103,24 +123,30 @@ async fn main() -> anyhow::Result<()> {
     let (writer, writer_rx) = unbounded_channel();
 
     let writer = twitch::Writer::new(writer);
+    let twitch_status = twitch::Status::default();
 
     tokio::spawn({
         let config = config.clone();
-        async move { twitch::connect(config, events_tx, writer_rx).await }
+        let status = twitch_status.clone();
+        async move { twitch::connect(config, events_tx, writer_rx, status).await }
     });
 
     let (tx, rx) = mpsc::unbounded_channel();
     let (req_tx, req_rx) = mpsc::unbounded_channel();
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
 
     tokio::spawn(
         bot::Bot::new(
             config,
+            bot::Settings::from_env(),
             events,
             writer,
             tx.clone(),
             req_tx,
+            cmd_tx,
             session.clone(),
             spotify_api_client,
+            bot_db,
         )
         .process(),
     );
@@ -128,8 +154,43 @@ async fn main() -> anyhow::Result<()> {
     eframe::run_native(
         "spotify-mistake",
         eframe::NativeOptions::default(),
-        Box::new(|cc| control::Control::create(cc, session, player, volume, tx, rx, req_rx)),
+        Box::new(|cc| {
+            control::Control::create(
+                cc,
+                session,
+                player,
+                volume,
+                tx,
+                rx,
+                req_rx,
+                cmd_rx,
+                twitch_status,
+                control_db,
+            )
+        }),
     )
     .unwrap();
     Ok(())
 }
+
+// these only touch the database, so nothing else has to be configured for them
+fn run_command(args: &[String]) -> anyhow::Result<()> {
+    let db = db::Connection::open(db::DEFAULT_PATH)?;
+
+    match args {
+        [cmd, format, path] if cmd == "export" => {
+            let summary = export::export_to(&db, format.parse()?, Path::new(path))?;
+            log::info!("exported {summary} to {path}");
+        }
+        [cmd, path] if cmd == "import" => {
+            let summary = export::import_from(&db, Path::new(path), export::Import::Everything)?;
+            log::info!("imported {summary} from {path}");
+        }
+        _ => anyhow::bail!(
+            "usage: {name} [export <json|csv|m3u> <path> | import <path>]",
+            name = env!("CARGO_PKG_NAME")
+        ),
+    }
+
+    Ok(())
+}
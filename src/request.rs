@@ -1,6 +1,9 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use librespot::{core::FileId, metadata::Track};
+use librespot::{
+    core::{FileId, Session, SpotifyId},
+    metadata::{image::ImageSize, Lyrics, Metadata as _},
+};
 
 use crate::{spotify_lyrics::SpotifyLyrics, twitch};
 
@@ -13,3 +16,114 @@ pub struct Request {
     pub lyrics: SpotifyLyrics,
     pub added_on: time::OffsetDateTime,
 }
+
+impl Request {
+    pub fn info(&self) -> TrackInfo {
+        TrackInfo {
+            track: Arc::clone(&self.track),
+            image_id: self.image_id,
+            lyrics: self.lyrics.clone(),
+        }
+    }
+}
+
+// this is the subset of the spotify metadata that we actually use
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub id: SpotifyId,
+    pub name: String,
+    pub artists: Vec<Artist>,
+    pub duration: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Artist {
+    pub id: SpotifyId,
+    pub name: String,
+}
+
+impl From<&librespot::metadata::Track> for Track {
+    fn from(track: &librespot::metadata::Track) -> Self {
+        Self {
+            id: track.id,
+            name: track.name.clone(),
+            artists: track
+                .artists
+                .iter()
+                .map(|artist| Artist {
+                    id: artist.id,
+                    name: artist.name.clone(),
+                })
+                .collect(),
+            duration: track.duration,
+        }
+    }
+}
+
+// everything about a request that comes from spotify, this is what gets cached in the database
+#[derive(Clone)]
+pub struct TrackInfo {
+    pub track: Arc<Track>,
+    pub image_id: Option<FileId>,
+    pub lyrics: SpotifyLyrics,
+}
+
+impl TrackInfo {
+    pub async fn fetch(session: &Session, id: SpotifyId) -> Result<Self, librespot::core::Error> {
+        let track = librespot::metadata::Track::get(session, &id).await?;
+
+        let image_id = track
+            .album
+            .covers
+            .iter()
+            .find_map(|c| (c.size == ImageSize::DEFAULT).then_some(c.id));
+
+        // not every track has lyrics, so this can't fail the lookup
+        let lyrics = Lyrics::get(session, &id)
+            .await
+            .ok()
+            .map(|lyrics| SpotifyLyrics::fix_up(lyrics, track.duration as _))
+            .unwrap_or_default();
+
+        Ok(Self {
+            track: Arc::new(Track::from(&track)),
+            image_id,
+            lyrics,
+        })
+    }
+
+    // transient failures are retried with an exponential backoff
+    pub async fn fetch_with_retry(
+        session: &Session,
+        id: SpotifyId,
+        retries: usize,
+    ) -> Option<Self> {
+        let mut backoff = Duration::from_millis(500);
+        for attempt in 0..=retries {
+            match Self::fetch(session, id).await {
+                Ok(info) => return Some(info),
+                Err(err) if attempt < retries && Self::is_transient(&err) => {
+                    log::debug!("retrying {id:?} in {backoff:?}: {err}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => {
+                    log::warn!("cannot look up {id:?}: {err}");
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    fn is_transient(err: &librespot::core::Error) -> bool {
+        use librespot::core::error::ErrorKind;
+        matches!(
+            err.kind,
+            ErrorKind::DeadlineExceeded
+                | ErrorKind::ResourceExhausted
+                | ErrorKind::Unavailable
+                | ErrorKind::Aborted
+        )
+    }
+}
@@ -43,6 +43,7 @@ impl SpotifyLyrics {
     }
 }
 
+#[derive(::serde::Serialize, ::serde::Deserialize)]
 pub struct LyricLine {
     pub start: usize,
     pub end: usize,
@@ -3,6 +3,8 @@ pub enum TabSelection {
     #[default]
     Queue,
     History,
+    Blocked,
+    Stats,
 }
 
 impl TabSelection {
@@ -10,6 +12,8 @@ impl TabSelection {
         match self {
             Self::Queue => "Queue",
             Self::History => "History",
+            Self::Blocked => "Blocked",
+            Self::Stats => "Stats",
         }
     }
 }
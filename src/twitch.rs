@@ -1,9 +1,9 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use egui::Color32;
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
     sync::mpsc::{UnboundedReceiver, UnboundedSender},
 };
 use twitch_message::{
@@ -15,7 +15,22 @@ use twitch_message::{
     IntoStatic, ParseResult,
 };
 
-use crate::util::{select2, Either};
+use crate::util::{env_or, select2, Either};
+
+mod backoff;
+use backoff::Backoff;
+
+#[cfg(test)]
+pub mod fake_server;
+
+mod outbox;
+use outbox::{Outbox, Pending};
+
+mod status;
+pub use status::{ConnectionState, Notice, Status};
+
+// Twitch drops messages longer than this
+pub const MAX_MESSAGE_LENGTH: usize = 500;
 
 #[derive(::serde::Serialize, ::serde::Deserialize, Clone)]
 pub struct User {
@@ -30,8 +45,15 @@ pub struct Config {
     pub pass: String,
     pub main_channel: String,
     pub spam_channel: String,
+    pub address: String,
+    pub tls: bool,
+    // how long the connection can be quiet before we ping, and how long the pong can take
+    pub ping_timeout: Duration,
 }
 
+// twitch_message only has the plaintext address
+pub const TWITCH_IRC_ADDRESS_TLS: &str = "irc.chat.twitch.tv:6697";
+
 pub struct Writer {
     sender: UnboundedSender<WriteKind>,
 }
@@ -76,175 +98,330 @@ pub enum WriteKind {
     },
 }
 
+// everything from chat that the bot has to know about
+pub enum Event {
+    Privmsg(Privmsg<'static>),
+    // a moderator deleted a single message
+    ClearMsg { msg_id: MsgId },
+    // a user was timed out or banned
+    ClearChat { user_id: UserId },
+}
+
 pub async fn connect(
     config: Config,
-    events: UnboundedSender<Privmsg<'static>>,
+    events: UnboundedSender<Event>,
     mut writer: UnboundedReceiver<WriteKind>,
+    status: Status,
 ) {
-    let mut success = false;
-    'outer: loop {
-        if success {
-            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-        }
-        success = true;
-
-        log::info!("connecting to Twitch");
-        let mut stream =
-            match tokio::net::TcpStream::connect(twitch_message::TWITCH_IRC_ADDRESS).await {
-                Ok(stream) => stream,
+    // this outlives the connection, so nothing is lost while reconnecting
+    let mut outbox = Outbox::new(env_or("TWITCH_BACKLOG", 50));
+
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5 * 60));
+    loop {
+        status.set_state(ConnectionState::Connecting);
+        log::info!(
+            "connecting to Twitch at {address}",
+            address = config.address
+        );
+
+        let outcome = match tokio::net::TcpStream::connect(&config.address).await {
+            Ok(stream) if config.tls => match tls_handshake(&config.address, stream).await {
+                Ok(stream) => {
+                    run(
+                        stream,
+                        &config,
+                        &events,
+                        &mut writer,
+                        &mut outbox,
+                        &status,
+                        &mut backoff,
+                    )
+                    .await
+                }
                 Err(err) => {
-                    log::warn!("cannot connect: {err}");
-                    continue;
+                    log::warn!("cannot establish tls: {err}");
+                    Outcome::Reconnect
                 }
-            };
+            },
+            Ok(stream) => {
+                run(
+                    stream,
+                    &config,
+                    &events,
+                    &mut writer,
+                    &mut outbox,
+                    &status,
+                    &mut backoff,
+                )
+                .await
+            }
+            Err(err) => {
+                log::warn!("cannot connect: {err}");
+                Outcome::Reconnect
+            }
+        };
+
+        match outcome {
+            Outcome::Reconnect => {}
+            // twitch is going away, and the next server is already waiting for us
+            Outcome::ReconnectNow => continue,
+            // retrying with the same credentials won't help
+            Outcome::AuthFailed(reason) => {
+                log::error!("cannot log in to Twitch: {reason}");
+                status.set_state(ConnectionState::Failed { reason });
+                break;
+            }
+            Outcome::Stop => break,
+        }
 
-        let register = register(&config.name, &config.pass, ALL_CAPABILITIES);
+        let delay = backoff.delay();
+        log::info!("reconnecting in {delay:?}");
+        status.set_state(ConnectionState::BackingOff {
+            until: Instant::now() + delay,
+        });
+        tokio::time::sleep(delay).await;
+    }
+}
 
-        if !write_all(&mut stream, register.to_string()).await {
-            log::warn!("cannot register");
-            continue;
-        }
+async fn tls_handshake(
+    address: &str,
+    stream: tokio::net::TcpStream,
+) -> anyhow::Result<tokio_native_tls::TlsStream<tokio::net::TcpStream>> {
+    // the certificate is checked against the host part of the address
+    let domain = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let connector = tokio_native_tls::native_tls::TlsConnector::new()?;
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(domain, stream)
+        .await?;
+    Ok(stream)
+}
 
-        let (read, mut write) = stream.split();
+enum Outcome {
+    Reconnect,
+    ReconnectNow,
+    AuthFailed(String),
+    Stop,
+}
 
-        let mut lines = BufReader::new(read).lines();
-        let mut our_name = <Option<String>>::default();
+async fn run<S>(
+    stream: S,
+    config: &Config,
+    events: &UnboundedSender<Event>,
+    writer: &mut UnboundedReceiver<WriteKind>,
+    outbox: &mut Outbox,
+    status: &Status,
+    backoff: &mut Backoff,
+) -> Outcome
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let register = register(&config.name, &config.pass, ALL_CAPABILITIES);
+
+    let (read, mut write) = tokio::io::split(stream);
+    if !write_all(&mut write, register.to_string()).await {
+        log::warn!("cannot register");
+        return Outcome::Reconnect;
+    }
 
-        #[derive(Default)]
-        enum PingTimeout {
-            Waiting {
-                token: String,
-            },
-            #[default]
-            Nothing,
-        }
+    let mut lines = BufReader::new(read).lines();
+    let mut our_name = <Option<String>>::default();
 
-        let mut timeout = PingTimeout::default();
+    #[derive(Default)]
+    enum PingTimeout {
+        Waiting {
+            token: String,
+        },
+        #[default]
+        Nothing,
+    }
 
-        'inner: loop {
-            let line = std::pin::pin!(lines.next_line());
-            let recv = std::pin::pin!(writer.recv());
+    let mut timeout = PingTimeout::default();
+
+    loop {
+        let line = std::pin::pin!(lines.next_line());
+        let recv = std::pin::pin!(writer.recv());
+        let incoming = std::pin::pin!(select2(line, recv));
+        // this wakes up when the next waiting message can be sent
+        let flush = std::pin::pin!(tokio::time::sleep(
+            outbox.wait().unwrap_or(Duration::from_secs(60 * 60))
+        ));
+
+        match {
+            match tokio::time::timeout(config.ping_timeout, select2(incoming, flush)).await {
+                Ok(ready) => ready,
+                Err(..) if matches!(timeout, PingTimeout::Waiting { .. }) => {
+                    log::warn!("timed out, and no ping waiting");
+                    return Outcome::Reconnect;
+                }
+                Err(..) => {
+                    let token = std::iter::repeat_with(fastrand::alphanumeric)
+                        .take(10)
+                        .collect::<String>();
 
-            match {
-                match tokio::time::timeout(Duration::from_secs(60), select2(line, recv)).await {
-                    Ok(ready) => ready,
-                    Err(..) if matches!(timeout, PingTimeout::Waiting { .. }) => {
-                        log::warn!("timed out, and no ping waiting");
-                        continue 'outer;
+                    timeout = PingTimeout::Waiting {
+                        token: token.clone(),
+                    };
+
+                    let ping = ping(&token);
+                    if write_all(&mut write, ping.to_string()).await {
+                        continue;
+                    } else {
+                        return Outcome::Reconnect;
+                    }
+                }
+            }
+        } {
+            Either::Left(Either::Left(Ok(Some(line)))) => {
+                let message = match twitch_message::parse(&line) {
+                    Ok(ParseResult { message, .. }) => message,
+                    Err(err) => {
+                        log::warn!(
+                            "cannot parse message: '{line}': {err}",
+                            line = line.escape_debug()
+                        );
+                        return Outcome::Reconnect;
                     }
-                    Err(..) => {
-                        let token = std::iter::repeat_with(fastrand::alphanumeric)
-                            .take(10)
-                            .collect::<String>();
-
-                        timeout = PingTimeout::Waiting {
-                            token: token.clone(),
-                        };
-
-                        let ping = ping(&token);
-                        if write_all(&mut write, ping.to_string()).await {
-                            continue 'inner;
-                        } else {
-                            continue 'outer;
+                };
+
+                match message.as_enum() {
+                    TwitchMessage::Privmsg(msg) => {
+                        if events.send(Event::Privmsg(msg.into_static())).is_err() {
+                            return Outcome::Stop;
                         }
                     }
-                }
-            } {
-                Either::Left(Ok(Some(line))) => {
-                    let message = match twitch_message::parse(&line) {
-                        Ok(ParseResult { message, .. }) => message,
-                        Err(err) => {
-                            log::warn!(
-                                "cannot parse message: '{line}': {err}",
-                                line = line.escape_debug()
-                            );
-                            continue 'outer;
+
+                    TwitchMessage::Reconnect(..) => {
+                        log::info!("Twitch asked us to reconnect");
+                        return Outcome::ReconnectNow;
+                    }
+
+                    TwitchMessage::Notice(notice) => {
+                        if is_auth_failure(&notice.message) {
+                            return Outcome::AuthFailed(notice.message.to_string());
                         }
-                    };
+                        log::warn!("notice from Twitch: {message}", message = notice.message);
+                        status.push_notice(&notice.message);
+                    }
 
-                    match message.as_enum() {
-                        TwitchMessage::Privmsg(msg) => {
-                            if events.send(msg.into_static()).is_err() {
-                                break 'outer;
-                            }
+                    TwitchMessage::ClearMsg(msg) => {
+                        let Some(msg_id) = msg.tags.get("target-msg-id") else { continue };
+                        let msg_id = MsgId::from(msg_id.to_string());
+                        if events.send(Event::ClearMsg { msg_id }).is_err() {
+                            return Outcome::Stop;
                         }
+                    }
 
-                        TwitchMessage::Ping(msg) => {
-                            let msg = pong(&msg.token);
-                            if !write_all(&mut write, msg.to_string()).await {
-                                continue 'outer;
-                            }
+                    // without a target the whole chat was cleared, which doesn't concern any requests
+                    TwitchMessage::ClearChat(clear) => {
+                        let Some(user_id) = clear.tags.get("target-user-id") else { continue };
+                        let user_id = UserId::from(user_id.to_string());
+                        if events.send(Event::ClearChat { user_id }).is_err() {
+                            return Outcome::Stop;
                         }
+                    }
 
-                        TwitchMessage::Pong(pong) => match &timeout {
-                            PingTimeout::Waiting { token } if token == &pong.token => {
-                                let _ = std::mem::take(&mut timeout);
-                            }
-                            PingTimeout::Waiting { .. } => {
-                                continue 'outer;
-                            }
-                            PingTimeout::Nothing => {}
-                        },
-
-                        TwitchMessage::Ready(ready) => {
-                            let _ = our_name.replace(ready.name.to_string());
-                            log::info!("IRC is ready");
+                    TwitchMessage::Ping(msg) => {
+                        let msg = pong(&msg.token);
+                        if !write_all(&mut write, msg.to_string()).await {
+                            return Outcome::Reconnect;
                         }
+                    }
 
-                        TwitchMessage::GlobalUserState(state) => {
-                            log::info!("Twitch is ready");
-                            log::debug!(
-                                "joining main channel: {channel}",
-                                channel = config.main_channel
-                            );
-                            if !write_all(&mut write, join(&config.main_channel).to_string()).await
-                            {
-                                continue 'outer;
-                            }
-
-                            log::debug!(
-                                "joining spam channel: {channel}",
-                                channel = config.spam_channel
-                            );
-                            if !write_all(&mut write, join(&config.spam_channel).to_string()).await
-                            {
-                                continue 'outer;
-                            }
+                    TwitchMessage::Pong(pong) => match &timeout {
+                        PingTimeout::Waiting { token } if token == &pong.token => {
+                            let _ = std::mem::take(&mut timeout);
+                        }
+                        PingTimeout::Waiting { .. } => {
+                            return Outcome::Reconnect;
                         }
-                        _ => {}
+                        PingTimeout::Nothing => {}
+                    },
+
+                    TwitchMessage::UserState(state) => {
+                        // this is sent once a channel has been joined
+                        status.set_state(ConnectionState::Joined);
+                        backoff.reset();
+                        outbox.update_user_state(&state.channel, state.tags.get("badges"));
                     }
-                }
 
-                Either::Right(Some(kind)) => {
-                    let channel = |target: ChannelTarget| match target {
-                        ChannelTarget::Main => &config.main_channel,
-                        ChannelTarget::Spam => &config.spam_channel,
-                    };
+                    TwitchMessage::Ready(ready) => {
+                        let _ = our_name.replace(ready.name.to_string());
+                        status.set_state(ConnectionState::Registered);
+                        log::info!("IRC is ready");
+                    }
 
-                    let data = match kind {
-                        WriteKind::Reply { target, id, data } => {
-                            reply(&id, channel(target), &data).to_string() //
+                    TwitchMessage::GlobalUserState(state) => {
+                        log::info!("Twitch is ready");
+                        log::debug!(
+                            "joining main channel: {channel}",
+                            channel = config.main_channel
+                        );
+                        if !write_all(&mut write, join(&config.main_channel).to_string()).await {
+                            return Outcome::Reconnect;
                         }
-                        WriteKind::Say { target, data } => {
-                            privmsg(channel(target), &data).to_string() //
+
+                        log::debug!(
+                            "joining spam channel: {channel}",
+                            channel = config.spam_channel
+                        );
+                        if !write_all(&mut write, join(&config.spam_channel).to_string()).await {
+                            return Outcome::Reconnect;
                         }
-                    };
+                    }
+                    _ => {}
+                }
+            }
 
-                    if !write_all(&mut write, data).await {
-                        log::warn!("cannot write");
-                        continue 'outer;
+            Either::Left(Either::Right(Some(kind))) => {
+                let channel = |target: ChannelTarget| match target {
+                    ChannelTarget::Main => &config.main_channel,
+                    ChannelTarget::Spam => &config.spam_channel,
+                };
+
+                match kind {
+                    WriteKind::Reply { target, id, data } => {
+                        outbox.push(channel(target), Some(id), &data)
+                    }
+                    WriteKind::Say { target, data } => {
+                        outbox.push(channel(target), None, &data) //
                     }
                 }
+            }
+
+            Either::Right(()) => {}
+
+            Either::Left(Either::Left(..)) => return Outcome::Reconnect,
+            Either::Left(Either::Right(..)) => return Outcome::Stop,
+        }
+
+        while let Some(Pending {
+            channel,
+            reply: id,
+            data,
+        }) = outbox.pop_ready()
+        {
+            let data = match id {
+                Some(id) => reply(&id, &channel, &data).to_string(),
+                None => privmsg(&channel, &data).to_string(),
+            };
 
-                Either::Left(..) => continue 'outer,
-                Either::Right(..) => break 'outer,
+            if !write_all(&mut write, data).await {
+                log::warn!("cannot write");
+                return Outcome::Reconnect;
             }
         }
     }
 }
 
+// twitch doesn't give these a msg-id, so the text is all there is to go on
+fn is_auth_failure(message: &str) -> bool {
+    ["Login authentication failed", "Improperly formatted auth"]
+        .iter()
+        .any(|failure| message.contains(failure))
+}
+
 async fn write_all(
-    stream: &mut (impl AsyncWrite + Unpin + Send + Sync),
+    stream: &mut (impl AsyncWrite + Unpin + Send),
     data: impl AsRef<[u8]> + Send + Sync,
 ) -> bool {
     if stream.write_all(data.as_ref()).await.is_ok() {
@@ -252,3 +429,155 @@ async fn write_all(
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::{fake_server::FakeServer, *};
+
+    fn config(address: &str, ping_timeout: Duration) -> Config {
+        Config {
+            name: String::from("bot"),
+            pass: String::from("oauth:hunter2"),
+            main_channel: String::from("main"),
+            spam_channel: String::from("spam"),
+            address: address.to_string(),
+            tls: false,
+            ping_timeout,
+        }
+    }
+
+    // this returns once the client has logged in and joined both channels
+    async fn joined(server: &mut FakeServer) {
+        server.expect("NICK bot").await;
+        server.expect("JOIN #main").await;
+        server.expect("JOIN #spam").await;
+    }
+
+    async fn next(events: &mut UnboundedReceiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .expect("an event")
+            .expect("the connection is running")
+    }
+
+    async fn relayed(
+        server: &FakeServer,
+        events: &mut UnboundedReceiver<Event>,
+        data: &str,
+    ) -> Privmsg<'static> {
+        let msg_id = server.say("viewer", "", data);
+        let Event::Privmsg(msg) = next(events).await else {
+            panic!("not a privmsg")
+        };
+        assert_eq!(msg.data, data);
+        assert_eq!(msg.msg_id().map(|id| id.as_str()), Some(msg_id.as_str()));
+        msg
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_asked_or_dropped() {
+        let mut server = FakeServer::start().await;
+        let (events_tx, mut events) = unbounded_channel();
+        let (_writer, writer) = unbounded_channel();
+        let config = config(&server.address, Duration::from_secs(60));
+        tokio::spawn(connect(config, events_tx, writer, Status::default()));
+
+        joined(&mut server).await;
+        relayed(&server, &mut events, "first").await;
+
+        server.reconnect();
+        joined(&mut server).await;
+        relayed(&server, &mut events, "after reconnecting").await;
+
+        // this one has to wait for the backoff
+        server.drop_client();
+        joined(&mut server).await;
+        relayed(&server, &mut events, "after being dropped").await;
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_a_ping_goes_unanswered() {
+        let mut server = FakeServer::start().await;
+        server.stop_answering_pings();
+
+        let (events_tx, mut events) = unbounded_channel();
+        let (_writer, writer) = unbounded_channel();
+        let status = Status::default();
+        let config = config(&server.address, Duration::from_millis(200));
+        tokio::spawn(connect(config, events_tx, writer, status.clone()));
+
+        joined(&mut server).await;
+        assert!(matches!(
+            status.state(),
+            ConnectionState::Registered | ConnectionState::Joined
+        ));
+
+        // nothing is said, so the client checks if the server is still there
+        server.expect("PING").await;
+        joined(&mut server).await;
+        relayed(&server, &mut events, "still here").await;
+    }
+
+    #[tokio::test]
+    async fn relays_deletions_timeouts_and_notices() {
+        let mut server = FakeServer::start().await;
+        let (events_tx, mut events) = unbounded_channel();
+        let (_writer, writer) = unbounded_channel();
+        let status = Status::default();
+        let config = config(&server.address, Duration::from_secs(60));
+        tokio::spawn(connect(config, events_tx, writer, status.clone()));
+
+        joined(&mut server).await;
+        let msg = relayed(&server, &mut events, "~req something").await;
+
+        let msg_id = msg.msg_id().expect("an id").as_str();
+        server.delete(msg_id);
+        let Event::ClearMsg { msg_id: deleted } = next(&mut events).await else {
+            panic!("not a deleted message")
+        };
+        assert_eq!(deleted.as_str(), msg_id);
+
+        let user_id = msg.user_id().expect("a user").as_str();
+        server.time_out("viewer");
+        let Event::ClearChat { user_id: timed_out } = next(&mut events).await else {
+            panic!("not a timeout")
+        };
+        assert_eq!(timed_out.as_str(), user_id);
+
+        // notices don't go to the bot, they're shown in the gui
+        server.notice("This room is now in slow mode.");
+        let shown = tokio::time::timeout(Duration::from_secs(10), async {
+            while status.notices().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        shown.await.expect("a notice");
+        let notices = status.notices();
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].message, "This room is now in slow mode.");
+        assert!(matches!(status.state(), ConnectionState::Joined));
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_the_login_is_rejected() {
+        let mut server = FakeServer::rejecting_logins().await;
+        let (events_tx, _events) = unbounded_channel();
+        let (_writer, writer) = unbounded_channel();
+        let status = Status::default();
+        let config = config(&server.address, Duration::from_secs(60));
+        let connection = tokio::spawn(connect(config, events_tx, writer, status.clone()));
+
+        server.expect("NICK bot").await;
+        tokio::time::timeout(Duration::from_secs(10), connection)
+            .await
+            .expect("the client to stop reconnecting")
+            .unwrap();
+
+        let ConnectionState::Failed { reason } = status.state() else {
+            panic!("the connection didn't fail")
+        };
+        assert!(reason.contains("Login authentication failed"), "{reason}");
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+// this doubles after every failed attempt, and picks a random point in the upper half so
+// clients that were dropped at the same time don't all come back at once
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub const fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            current: base,
+        }
+    }
+
+    pub fn delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay.mul_f64(0.5 + fastrand::f64() * 0.5)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_the_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        for expected in [1, 2, 4, 5, 5] {
+            let max = Duration::from_secs(expected);
+            let delay = backoff.delay();
+            // the jitter only ever shortens the delay, to at most half
+            assert!(delay <= max && delay >= max / 2, "{delay:?} for {max:?}");
+        }
+    }
+
+    #[test]
+    fn jitter_stays_in_the_upper_half() {
+        let max = Duration::from_millis(1000);
+        for _ in 0..1000 {
+            let delay = Backoff::new(max, max).delay();
+            assert!((max / 2..=max).contains(&delay), "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..5 {
+            backoff.delay();
+        }
+        assert!(backoff.delay() >= Duration::from_secs(16));
+
+        backoff.reset();
+        assert!(backoff.delay() <= Duration::from_secs(1));
+        assert!(backoff.delay() <= Duration::from_secs(2));
+    }
+}
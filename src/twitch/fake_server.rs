@@ -0,0 +1,349 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::util::{select2, Either};
+
+const HOST: &str = "tmi.twitch.tv";
+
+// how long `expect` waits for the client before the test fails
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Script {
+    Say {
+        name: String,
+        badges: String,
+        data: String,
+        msg_id: String,
+    },
+    // a moderator deleted this message
+    Delete {
+        msg_id: String,
+    },
+    // a moderator timed this user out
+    TimeOut {
+        name: String,
+    },
+    Notice {
+        message: String,
+    },
+    Reconnect,
+    Drop,
+    Silence,
+}
+
+// a stand-in for the twitch irc server, so the connection and the bot can be tested without an account.
+// it accepts one client at a time, and a new one once that has gone away
+pub struct FakeServer {
+    pub address: String,
+    script: UnboundedSender<Script>,
+    received: UnboundedReceiver<String>,
+}
+
+impl FakeServer {
+    pub async fn start() -> Self {
+        Self::start_with(true).await
+    }
+
+    // this answers every login with the notice twitch sends for a bad token
+    pub async fn rejecting_logins() -> Self {
+        Self::start_with(false).await
+    }
+
+    async fn start_with(accept_logins: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("a local port");
+        let address = listener.local_addr().expect("a bound address").to_string();
+
+        let (script, mut script_rx) = unbounded_channel();
+        let (received_tx, received) = unbounded_channel();
+        tokio::spawn(async move {
+            let mut state = State {
+                ids: HashMap::new(),
+                accept_logins,
+                silent: false,
+                received: received_tx,
+            };
+            while let Ok((stream, _)) = listener.accept().await {
+                match state.serve(stream, &mut script_rx).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => log::warn!("client error: {err}"),
+                }
+            }
+        });
+
+        Self {
+            address,
+            script,
+            received,
+        }
+    }
+
+    // this speaks in the first channel the client joined, and returns the id of the message
+    pub fn say(&self, name: &str, badges: &str, data: &str) -> String {
+        let msg_id = uuid::Uuid::new_v4().to_string();
+        self.send(Script::Say {
+            name: name.to_string(),
+            badges: badges.to_string(),
+            data: data.to_string(),
+            msg_id: msg_id.clone(),
+        });
+        msg_id
+    }
+
+    pub fn delete(&self, msg_id: &str) {
+        self.send(Script::Delete {
+            msg_id: msg_id.to_string(),
+        })
+    }
+
+    pub fn time_out(&self, name: &str) {
+        self.send(Script::TimeOut {
+            name: name.to_string(),
+        })
+    }
+
+    // this goes to the first channel the client joined, or to everyone before that
+    pub fn notice(&self, message: &str) {
+        self.send(Script::Notice {
+            message: message.to_string(),
+        })
+    }
+
+    // this asks the client to reconnect, and then drops it
+    pub fn reconnect(&self) {
+        self.send(Script::Reconnect)
+    }
+
+    pub fn drop_client(&self) {
+        self.send(Script::Drop)
+    }
+
+    pub fn stop_answering_pings(&self) {
+        self.send(Script::Silence)
+    }
+
+    // this waits for a line from the client with `needle` in it, skipping everything before it
+    pub async fn expect(&mut self, needle: &str) -> String {
+        let wait = async {
+            while let Some(line) = self.received.recv().await {
+                if line.contains(needle) {
+                    return line;
+                }
+            }
+            panic!("the server stopped")
+        };
+
+        tokio::time::timeout(TIMEOUT, wait)
+            .await
+            .unwrap_or_else(|_| panic!("the client didn't send {needle:?}"))
+    }
+
+    fn send(&self, script: Script) {
+        self.script.send(script).expect("server is running")
+    }
+}
+
+struct State {
+    // user ids have to stay the same between connections
+    ids: HashMap<String, usize>,
+    accept_logins: bool,
+    silent: bool,
+    received: UnboundedSender<String>,
+}
+
+struct Client {
+    nick: String,
+    channel: Option<String>,
+}
+
+impl State {
+    // this is false once the test is over
+    async fn serve(
+        &mut self,
+        stream: TcpStream,
+        script: &mut UnboundedReceiver<Script>,
+    ) -> anyhow::Result<bool> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        let mut client = Client {
+            nick: String::from("justinfan"),
+            channel: None,
+        };
+
+        loop {
+            let event = {
+                let line = std::pin::pin!(lines.next_line());
+                let next = std::pin::pin!(script.recv());
+                select2(line, next).await
+            };
+
+            match event {
+                Either::Left(Ok(Some(line))) => {
+                    let _ = self.received.send(line.clone());
+                    self.respond(&mut write, &mut client, &line).await?
+                }
+                Either::Left(Ok(None)) => return Ok(true),
+                Either::Left(Err(err)) => return Err(err.into()),
+
+                Either::Right(Some(Script::Say {
+                    name,
+                    badges,
+                    data,
+                    msg_id,
+                })) => {
+                    let Some(channel) = &client.channel else {
+                        log::warn!("nothing has been joined yet, dropping: {data}");
+                        continue
+                    };
+                    let msg = self.privmsg(channel, &name, &badges, &data, &msg_id);
+                    send(&mut write, &msg).await?;
+                }
+                Either::Right(Some(Script::Delete { msg_id })) => {
+                    let Some(channel) = &client.channel else {
+                        log::warn!("nothing has been joined yet, not deleting: {msg_id}");
+                        continue
+                    };
+                    let msg = format!(
+                        "@login=;room-id={room_id};target-msg-id={msg_id};tmi-sent-ts={ts} \
+                         :{HOST} CLEARMSG #{channel} :deleted",
+                        room_id = self.id_of(channel),
+                        ts = time::OffsetDateTime::now_utc().unix_timestamp() * 1000,
+                    );
+                    send(&mut write, &msg).await?;
+                }
+                Either::Right(Some(Script::TimeOut { name })) => {
+                    let Some(channel) = &client.channel else {
+                        log::warn!("nothing has been joined yet, not timing out: {name}");
+                        continue
+                    };
+                    let login = name.to_lowercase();
+                    let msg = format!(
+                        "@ban-duration=600;room-id={room_id};target-user-id={user_id};\
+                         tmi-sent-ts={ts} :{HOST} CLEARCHAT #{channel} :{login}",
+                        room_id = self.id_of(channel),
+                        user_id = self.id_of(&login),
+                        ts = time::OffsetDateTime::now_utc().unix_timestamp() * 1000,
+                    );
+                    send(&mut write, &msg).await?;
+                }
+                Either::Right(Some(Script::Notice { message })) => {
+                    let target = client
+                        .channel
+                        .as_ref()
+                        .map_or_else(|| String::from("*"), |channel| format!("#{channel}"));
+                    send(&mut write, &format!(":{HOST} NOTICE {target} :{message}")).await?;
+                }
+                Either::Right(Some(Script::Reconnect)) => {
+                    send(&mut write, &format!(":{HOST} RECONNECT")).await?;
+                    return Ok(true);
+                }
+                Either::Right(Some(Script::Drop)) => return Ok(true),
+                Either::Right(Some(Script::Silence)) => self.silent = true,
+                Either::Right(None) => return Ok(false),
+            }
+        }
+    }
+
+    async fn respond(
+        &mut self,
+        write: &mut (impl AsyncWrite + Unpin + Send),
+        client: &mut Client,
+        line: &str,
+    ) -> anyhow::Result<()> {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "CAP" => {
+                let caps = rest.strip_prefix("REQ ").unwrap_or(rest);
+                let caps = caps.strip_prefix(':').unwrap_or(caps);
+                send(write, &format!(":{HOST} CAP * ACK :{caps}")).await
+            }
+            "NICK" if !self.accept_logins => {
+                let failed = format!(":{HOST} NOTICE * :Login authentication failed");
+                send(write, &failed).await
+            }
+            "NICK" => {
+                rest.clone_into(&mut client.nick);
+                self.welcome(write, &client.nick).await
+            }
+            "JOIN" => {
+                let nick = &client.nick;
+                let channel = rest.trim_start_matches('#');
+                let joined = format!(
+                    ":{nick}!{nick}@{nick}.{HOST} JOIN #{channel}\r\n\
+                     @badge-info=;badges=;color=;display-name={nick};emote-sets=0;\
+                     mod=0;subscriber=0;user-type= :{HOST} USERSTATE #{channel}\r\n\
+                     @room-id={id} :{HOST} ROOMSTATE #{channel}",
+                    id = self.id_of(channel),
+                );
+                // chat from the test goes to the first channel that was joined
+                client.channel.get_or_insert_with(|| channel.to_string());
+                send(write, &joined).await
+            }
+            "PING" if !self.silent => send(write, &format!(":{HOST} PONG {HOST} {rest}")).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn welcome(
+        &mut self,
+        write: &mut (impl AsyncWrite + Unpin + Send),
+        nick: &str,
+    ) -> anyhow::Result<()> {
+        let welcome = [
+            format!(":{HOST} 001 {nick} :Welcome, GLHF!"),
+            format!(":{HOST} 002 {nick} :Your host is {HOST}"),
+            format!(":{HOST} 003 {nick} :This server is rather new"),
+            format!(":{HOST} 004 {nick} :-"),
+            format!(":{HOST} 375 {nick} :-"),
+            format!(":{HOST} 372 {nick} :You are in a maze of twisty passages, all alike."),
+            format!(":{HOST} 376 {nick} :>"),
+            format!(
+                "@badge-info=;badges=;color=;display-name={nick};emote-sets=0;user-id={id};\
+                 user-type= :{HOST} GLOBALUSERSTATE",
+                id = self.id_of(nick)
+            ),
+        ];
+        send(write, &welcome.join("\r\n")).await
+    }
+
+    fn privmsg(
+        &mut self,
+        channel: &str,
+        name: &str,
+        badges: &str,
+        data: &str,
+        msg_id: &str,
+    ) -> String {
+        let login = name.to_lowercase();
+        format!(
+            "@badge-info=;badges={badges};color=#1E90FF;display-name={name};emotes=;\
+             first-msg=0;flags=;id={msg_id};mod={moderator};room-id={room_id};subscriber=0;\
+             tmi-sent-ts={ts};turbo=0;user-id={user_id};user-type= \
+             :{login}!{login}@{login}.{HOST} PRIVMSG #{channel} :{data}",
+            moderator = u8::from(badges.contains("moderator")),
+            room_id = self.id_of(channel),
+            ts = time::OffsetDateTime::now_utc().unix_timestamp() * 1000,
+            user_id = self.id_of(&login),
+        )
+    }
+
+    fn id_of(&mut self, name: &str) -> usize {
+        let next = self.ids.len() + 1000;
+        *self.ids.entry(name.to_lowercase()).or_insert(next)
+    }
+}
+
+async fn send(write: &mut (impl AsyncWrite + Unpin + Send), data: &str) -> anyhow::Result<()> {
+    write.write_all(data.as_bytes()).await?;
+    write.write_all(b"\r\n").await?;
+    write.flush().await?;
+    Ok(())
+}
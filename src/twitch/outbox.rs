@@ -0,0 +1,288 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use hashbrown::HashSet;
+use twitch_message::messages::types::MsgId;
+
+// Twitch allows 20 messages per 30 seconds, or 100 in channels where we're a moderator or vip.
+// the window slides, so a full burst plus a window's worth of refill has to stay under the limit.
+// the larger limit is for the whole account, so every message counts against it
+const WINDOW: Duration = Duration::from_secs(30);
+const NORMAL: Budget = Budget {
+    limit: 20,
+    burst: 5,
+};
+const ELEVATED: Budget = Budget {
+    limit: 100,
+    burst: 20,
+};
+
+struct Budget {
+    limit: u32,
+    burst: u32,
+}
+
+struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(Budget { limit, burst }: Budget) -> Self {
+        Self {
+            capacity: f64::from(burst),
+            per_sec: f64::from(limit - burst) / WINDOW.as_secs_f64(),
+            tokens: f64::from(burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.refilled = now;
+    }
+
+    fn ready(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0
+    }
+
+    // how long until a token is available
+    fn wait(&mut self) -> Duration {
+        self.refill();
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.per_sec).max(0.0))
+    }
+}
+
+pub struct Pending {
+    pub channel: String,
+    pub reply: Option<MsgId>,
+    pub data: String,
+}
+
+pub struct Outbox {
+    backlog: VecDeque<Pending>,
+    max_backlog: usize,
+    normal: TokenBucket,
+    account: TokenBucket,
+    elevated_in: HashSet<String>,
+}
+
+impl Outbox {
+    pub fn new(max_backlog: usize) -> Self {
+        Self {
+            backlog: VecDeque::new(),
+            max_backlog: max_backlog.max(1),
+            normal: TokenBucket::new(NORMAL),
+            account: TokenBucket::new(ELEVATED),
+            elevated_in: HashSet::new(),
+        }
+    }
+
+    // long messages are split up, and the oldest messages are dropped if too much is waiting
+    pub fn push(&mut self, channel: &str, reply: Option<MsgId>, data: &str) {
+        for data in split(data, super::MAX_MESSAGE_LENGTH) {
+            if self.backlog.len() == self.max_backlog {
+                if let Some(dropped) = self.backlog.pop_front() {
+                    log::warn!(
+                        "backlog is full, dropping: {data}",
+                        data = dropped.data.escape_debug()
+                    );
+                }
+            }
+
+            self.backlog.push_back(Pending {
+                channel: channel.to_string(),
+                reply: reply.clone(),
+                data,
+            });
+        }
+    }
+
+    // USERSTATE is sent whenever we join or speak in a channel, with our badges in it
+    pub fn update_user_state(&mut self, channel: &str, badges: Option<&str>) {
+        let elevated = badges.map_or(false, |badges| {
+            badges.split(',').any(|badge| {
+                matches!(
+                    badge.split('/').next(),
+                    Some("broadcaster" | "moderator" | "vip")
+                )
+            })
+        });
+
+        let channel = normalize(channel);
+        if elevated {
+            self.elevated_in.insert(channel);
+        } else {
+            self.elevated_in.remove(&channel);
+        }
+    }
+
+    // this is the next message, if its budget allows it to be sent now
+    pub fn pop_ready(&mut self) -> Option<Pending> {
+        let front = self.backlog.front()?;
+        let limited = !self.is_elevated(&front.channel);
+        if !self.account.ready() || (limited && !self.normal.ready()) {
+            return None;
+        }
+
+        self.account.take();
+        if limited {
+            self.normal.take();
+        }
+        self.backlog.pop_front()
+    }
+
+    // this is how long until the next message can be sent, if there is one
+    pub fn wait(&mut self) -> Option<Duration> {
+        let front = self.backlog.front()?;
+        if self.is_elevated(&front.channel) {
+            return Some(self.account.wait());
+        }
+        Some(self.account.wait().max(self.normal.wait()))
+    }
+
+    fn is_elevated(&self, channel: &str) -> bool {
+        self.elevated_in.contains(&normalize(channel))
+    }
+}
+
+fn normalize(channel: &str) -> String {
+    channel.trim_start_matches('#').to_lowercase()
+}
+
+// this splits on whitespace, only cutting up words that are longer than `max` bytes on their own
+fn split(data: &str, max: usize) -> Vec<String> {
+    if data.len() <= max {
+        return vec![data.to_string()];
+    }
+
+    let mut parts = vec![];
+    let mut part = String::new();
+    for mut word in data.split_whitespace() {
+        while word.len() > max {
+            if !part.is_empty() {
+                parts.push(std::mem::take(&mut part));
+            }
+            let at = (1..=max)
+                .rev()
+                .find(|&i| word.is_char_boundary(i))
+                .unwrap_or(word.len());
+            parts.push(word[..at].to_string());
+            word = &word[at..];
+        }
+
+        if !part.is_empty() && part.len() + 1 + word.len() > max {
+            parts.push(std::mem::take(&mut part));
+        }
+        if !part.is_empty() {
+            part.push(' ');
+        }
+        part.push_str(word);
+    }
+
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELEVATED_BADGES: Option<&str> = Some("moderator/1");
+
+    #[test]
+    fn split_keeps_words_together() {
+        assert_eq!(split("short", 10), ["short"]);
+        assert_eq!(split("one two three four", 9), ["one two", "three", "four"]);
+        assert_eq!(split("abcdefghij klm", 4), ["abcd", "efgh", "ij", "klm"]);
+    }
+
+    #[test]
+    fn split_cuts_on_char_boundaries() {
+        let max = crate::twitch::MAX_MESSAGE_LENGTH;
+        // these are 3 bytes each, and 500 isn't a multiple of 3
+        let data = "\u{266a}".repeat(400);
+        let parts = split(&data, max);
+
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.len() <= max));
+        assert_eq!(parts[0].len(), 498);
+        assert_eq!(parts.concat(), data);
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(NORMAL);
+        for _ in 0..NORMAL.burst {
+            assert!(bucket.ready());
+            bucket.take();
+        }
+        assert!(!bucket.ready());
+
+        // 15 messages over the rest of the window, so one every two seconds
+        let wait = bucket.wait();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+
+        bucket.refilled -= Duration::from_secs(2);
+        assert!(bucket.ready());
+        assert_eq!(bucket.wait(), Duration::ZERO);
+
+        // it never holds more than a burst
+        bucket.refilled -= WINDOW;
+        bucket.refill();
+        assert_eq!(bucket.tokens, f64::from(NORMAL.burst));
+    }
+
+    #[test]
+    fn elevated_channels_get_a_larger_budget() {
+        let mut outbox = Outbox::new(100);
+        outbox.update_user_state("#main", ELEVATED_BADGES);
+        outbox.update_user_state("#spam", Some("subscriber/12"));
+
+        for i in 0..30 {
+            outbox.push("spam", None, &i.to_string());
+        }
+        let sent = std::iter::from_fn(|| outbox.pop_ready()).count();
+        assert_eq!(sent, NORMAL.burst as usize);
+        assert!(outbox.wait().unwrap() > Duration::ZERO);
+
+        let mut outbox = Outbox::new(100);
+        outbox.update_user_state("#main", ELEVATED_BADGES);
+        for i in 0..30 {
+            outbox.push("main", None, &i.to_string());
+        }
+        let sent = std::iter::from_fn(|| outbox.pop_ready()).count();
+        assert_eq!(sent, ELEVATED.burst as usize);
+    }
+
+    #[test]
+    fn every_message_counts_against_the_account() {
+        let mut outbox = Outbox::new(100);
+        outbox.update_user_state("#main", ELEVATED_BADGES);
+
+        // the normal channel uses up some of the account's burst
+        for i in 0..NORMAL.burst {
+            outbox.push("spam", None, &i.to_string());
+        }
+        for i in 0..ELEVATED.burst {
+            outbox.push("main", None, &i.to_string());
+        }
+
+        let sent = std::iter::from_fn(|| outbox.pop_ready()).count();
+        assert_eq!(sent, ELEVATED.burst as usize);
+        assert_eq!(outbox.backlog.len(), NORMAL.burst as usize);
+    }
+}
@@ -0,0 +1,89 @@
+use std::{collections::VecDeque, sync::Arc, time::Instant};
+
+use egui::{mutex::Mutex, Color32};
+
+use crate::util::format_remaining;
+
+// only the most recent notices are kept
+const MAX_NOTICES: usize = 20;
+
+#[derive(Clone)]
+pub struct Notice {
+    pub message: String,
+    pub received: Instant,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Registered,
+    Joined,
+    BackingOff {
+        until: Instant,
+    },
+    // this isn't retried, something has to be fixed first
+    Failed {
+        reason: String,
+    },
+}
+
+impl ConnectionState {
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Connecting => "connecting to Twitch".to_string(),
+            Self::Registered => "logged in, joining channels".to_string(),
+            Self::Joined => "connected".to_string(),
+            Self::BackingOff { until } => format!(
+                "disconnected, retrying in {}",
+                format_remaining(until.saturating_duration_since(Instant::now()))
+            ),
+            Self::Failed { reason } => format!("cannot connect: {reason}"),
+        }
+    }
+
+    pub const fn color(&self) -> Color32 {
+        match self {
+            Self::Connecting | Self::Registered => Color32::YELLOW,
+            Self::Joined => Color32::GREEN,
+            Self::BackingOff { .. } => Color32::LIGHT_RED,
+            Self::Failed { .. } => Color32::RED,
+        }
+    }
+}
+
+// this is how the connection tells the gui what twitch said
+#[derive(Clone, Default)]
+pub struct Status {
+    state: Arc<Mutex<ConnectionState>>,
+    notices: Arc<Mutex<VecDeque<Notice>>>,
+}
+
+impl Status {
+    pub fn state(&self) -> ConnectionState {
+        self.state.lock().clone()
+    }
+
+    pub fn set_state(&self, state: ConnectionState) {
+        *self.state.lock() = state
+    }
+
+    pub fn push_notice(&self, message: impl ToString) {
+        let mut notices = self.notices.lock();
+        if notices.len() == MAX_NOTICES {
+            notices.pop_front();
+        }
+        notices.push_back(Notice {
+            message: message.to_string(),
+            received: Instant::now(),
+        });
+    }
+
+    pub fn notices(&self) -> Vec<Notice> {
+        self.notices.lock().iter().cloned().collect()
+    }
+
+    pub fn clear_notices(&self) {
+        self.notices.lock().clear()
+    }
+}
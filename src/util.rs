@@ -16,6 +16,27 @@ where
     }
 }
 
+// reads `key` from the environment, using `default` if its missing or invalid
+pub fn env_or<T>(key: &str, default: T) -> T
+where
+    T: std::str::FromStr,
+{
+    std::env::var(key)
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn format_remaining(dur: std::time::Duration) -> String {
+    let s = dur.as_secs().max(1);
+    let (h, m, s) = (s / (60 * 60), (s / 60) % 60, s % 60);
+    match (h, m) {
+        (0, 0) => format!("{s}s"),
+        (0, m) => format!("{m}m {s}s"),
+        (h, m) => format!("{h}h {m}m"),
+    }
+}
+
 pub fn format_duration(s: u32) -> String {
     let s = s / 1000;
     let (h, m, s) = (s / (60 * 60), (s / 60) % 60, s % 60);
@@ -24,3 +45,26 @@ pub fn format_duration(s: u32) -> String {
     }
     format!("{m:02}:{s:02}")
 }
+
+// packs items into as few lines as possible, with each line at most `max` bytes
+pub fn pack_lines<T>(items: impl IntoIterator<Item = T>, sep: &str, max: usize) -> Vec<String>
+where
+    T: AsRef<str>,
+{
+    let mut lines = vec![];
+    let mut line = String::new();
+    for item in items {
+        let item = item.as_ref();
+        if !line.is_empty() && line.len() + sep.len() + item.len() > max {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push_str(sep);
+        }
+        line.push_str(item);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
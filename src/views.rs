@@ -1,3 +1,9 @@
+mod blocklist_view;
+pub use blocklist_view::BlocklistView;
+
+mod export_menu;
+pub use export_menu::ExportMenu;
+
 mod history_view;
 pub use history_view::HistoryView;
 
@@ -7,8 +13,14 @@ pub use image_view::ImageView;
 mod list_view;
 pub use list_view::ListView;
 
+mod notices_menu;
+pub use notices_menu::NoticesMenu;
+
 mod queue_view;
 pub use queue_view::QueueView;
 
 mod request_view;
 pub use request_view::RequestView;
+
+mod stats_view;
+pub use stats_view::{DateRange, StatsSnapshot, StatsView};
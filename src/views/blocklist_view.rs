@@ -0,0 +1,43 @@
+use egui::ScrollArea;
+
+use crate::db;
+
+pub struct BlocklistView<'a> {
+    pub blocklist: &'a mut Vec<db::Blocked>,
+    pub db: &'a db::Connection,
+}
+
+impl<'a> BlocklistView<'a> {
+    pub fn display(self, ui: &mut egui::Ui) {
+        let mut remove = None;
+
+        ScrollArea::vertical().show(ui, |ui| {
+            if self.blocklist.is_empty() {
+                ui.label("Nothing is blocked");
+                return;
+            }
+
+            for (i, blocked) in self.blocklist.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    // TODO make this a context menu
+                    if ui.small_button("✔").on_hover_text("Unblock").clicked() {
+                        remove.replace(i);
+                    }
+
+                    ui.monospace(blocked.kind.as_str());
+                    ui.strong(&blocked.value);
+                    ui.weak(format!("(blocked by {})", blocked.added_by));
+
+                    ui.allocate_space(ui.available_size_before_wrap());
+                });
+            }
+
+            ui.allocate_space(ui.available_size_before_wrap());
+        });
+
+        if let Some(index) = remove {
+            let blocked = self.blocklist.remove(index);
+            self.db.unblock(blocked.kind, &blocked.value);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use crate::{
+    db,
+    export::{self, Format, Import},
+};
+
+pub struct ExportMenu<'a> {
+    pub path: &'a mut String,
+    pub status: &'a mut Option<String>,
+    pub db: &'a db::Connection,
+}
+
+impl<'a> ExportMenu<'a> {
+    pub fn display(self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("File");
+            ui.text_edit_singleline(self.path);
+        });
+
+        ui.separator();
+
+        let path = Path::new(self.path.as_str());
+        for format in [Format::Json, Format::Csv, Format::M3u] {
+            let label = format!("Export {}", format.as_str().to_uppercase());
+            if ui.button(label).clicked() {
+                let status = match export::export_to(self.db, format, path) {
+                    Ok(summary) => format!("exported {summary} to {}", path.display()),
+                    Err(err) => format!("cannot export to {}: {err}", path.display()),
+                };
+                self.status.replace(status);
+                ui.close_menu();
+            }
+        }
+
+        let import = ui
+            .button("Import JSON")
+            .on_hover_text("the queue is only imported by the `import` command");
+        if import.clicked() {
+            // the loaded history isn't touched, so this is picked up on the next start
+            let status = match export::import_from(self.db, path, Import::WithoutQueue) {
+                Ok(summary) => format!("imported {summary}, restart to load them"),
+                Err(err) => format!("cannot import {}: {err}", path.display()),
+            };
+            self.status.replace(status);
+            ui.close_menu();
+        }
+    }
+}
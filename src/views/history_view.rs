@@ -8,6 +8,7 @@ use crate::{
 
 pub struct HistoryView<'a> {
     pub list_view: ListView<'a>,
+    pub loading: Option<(usize, usize)>,
     pub history: &'a mut history::History,
     pub queue: &'a mut VecDeque<Request>,
     pub db: &'a db::Connection,
@@ -15,6 +16,11 @@ pub struct HistoryView<'a> {
 
 impl<'a> HistoryView<'a> {
     pub fn display(self, ui: &mut egui::Ui) {
+        if let Some((done, total)) = self.loading {
+            ui.label(format!("loading {done}/{total}"));
+            return;
+        }
+
         match self.list_view.display(
             ui,
             "History is empty",
@@ -27,14 +33,16 @@ impl<'a> HistoryView<'a> {
             self.history.requests.iter(),
         ) {
             Action::Add { request } => {
-                self.db.queue(&request);
+                if let Err(err) = self.db.queue(&request) {
+                    log::warn!("cannot persist {}: {err}", request.id);
+                }
                 self.queue.push_back(request);
             }
             Action::Remove { index } => {
                 let req = self.history.requests.remove(index);
                 self.db.remove_from_history(&req);
             }
-            Action::Nothing => {}
+            Action::Move { .. } | Action::Nothing => {}
         }
     }
 }
@@ -1,4 +1,4 @@
-use egui::{ScrollArea, TextStyle};
+use egui::{CursorIcon, Label, Rect, ScrollArea, Sense, TextStyle};
 
 use crate::{
     image_cache,
@@ -9,9 +9,24 @@ use crate::{
 
 pub struct ListView<'a> {
     pub cache: &'a mut image_cache::ImageCache,
+    pub reorderable: bool,
 }
 
 impl<'a> ListView<'a> {
+    pub fn new(cache: &'a mut image_cache::ImageCache) -> Self {
+        Self {
+            cache,
+            reorderable: false,
+        }
+    }
+
+    pub fn with_reordering(self) -> Self {
+        Self {
+            reorderable: true,
+            ..self
+        }
+    }
+
     pub fn display<'i>(
         self,
         ui: &mut egui::Ui,
@@ -22,6 +37,7 @@ impl<'a> ListView<'a> {
     ) -> Action {
         let mut remove = None;
         let mut add = None;
+        let mut moved = None;
 
         ScrollArea::vertical().show(ui, |ui| {
             if is_empty {
@@ -33,11 +49,35 @@ impl<'a> ListView<'a> {
             let space = ui.fonts(|f| f.glyph_width(&fid, ' '));
             let height = ui.text_style_height(&TextStyle::Body);
 
+            let drag_id = ui.id().with("list-drag");
+            let dragging = ui.data(|d| d.get_temp::<usize>(drag_id));
+            let mut rows = Vec::<Rect>::new();
+
             // TODO allow searching by user (e.g. click on user name and show all songs from them)
-            // TODO allow reordering
             // TODO use a table here
             for (i, next) in items.enumerate() {
-                ui.horizontal(|ui| {
+                let row = ui.horizontal(|ui| {
+                    if self.reorderable {
+                        let handle = ui
+                            .add(Label::new("☰").sense(Sense::drag()))
+                            .on_hover_cursor(CursorIcon::Grab);
+                        if handle.drag_started() {
+                            ui.data_mut(|d| d.insert_temp(drag_id, i));
+                        }
+
+                        // TODO make this a context menu
+                        if ui.small_button("⏫").on_hover_text("Move to top").clicked() {
+                            moved.replace((i, 0));
+                        }
+                        if ui
+                            .small_button("⏬")
+                            .on_hover_text("Move to bottom")
+                            .clicked()
+                        {
+                            moved.replace((i, usize::MAX));
+                        }
+                    }
+
                     // TODO make this a context menu
                     buttons(ui, &mut add, next);
 
@@ -65,6 +105,34 @@ impl<'a> ListView<'a> {
 
                     ui.allocate_space(ui.available_size_before_wrap());
                 });
+                rows.push(row.response.rect);
+            }
+
+            if let (Some(from), Some(pos)) = (dragging, ui.ctx().pointer_latest_pos()) {
+                // this is where the item would be inserted
+                let target = rows
+                    .iter()
+                    .position(|rect| pos.y < rect.center().y)
+                    .unwrap_or(rows.len());
+
+                let y = rows
+                    .get(target)
+                    .map(|rect| rect.top())
+                    .or_else(|| rows.last().map(|rect| rect.bottom()))
+                    .unwrap_or_default();
+
+                ui.painter().hline(
+                    ui.max_rect().x_range(),
+                    y,
+                    (2.0, ui.visuals().selection.bg_fill),
+                );
+                ui.ctx().set_cursor_icon(CursorIcon::Grabbing);
+
+                if ui.input(|i| i.pointer.any_released()) {
+                    ui.data_mut(|d| d.remove::<usize>(drag_id));
+                    let to = if target > from { target - 1 } else { target };
+                    moved.replace((from, to));
+                }
             }
 
             ui.allocate_space(ui.available_size_before_wrap());
@@ -72,6 +140,11 @@ impl<'a> ListView<'a> {
 
         remove
             .map(|index| Action::Remove { index })
+            .or_else(|| {
+                moved
+                    .filter(|(from, to)| from != to)
+                    .map(|(from, to)| Action::Move { from, to })
+            })
             .or_else(|| add.map(|request| Action::Add { request }))
             .unwrap_or_default()
     }
@@ -85,6 +158,11 @@ pub enum Action {
     Remove {
         index: usize,
     },
+    // `to` is the index after the item has been removed
+    Move {
+        from: usize,
+        to: usize,
+    },
     #[default]
     Nothing,
 }
@@ -0,0 +1,27 @@
+use crate::{twitch, util::format_remaining};
+
+pub struct NoticesMenu<'a> {
+    pub notices: &'a [twitch::Notice],
+    pub status: &'a twitch::Status,
+}
+
+impl<'a> NoticesMenu<'a> {
+    pub fn display(self, ui: &mut egui::Ui) {
+        for notice in self.notices.iter().rev() {
+            ui.horizontal(|ui| {
+                ui.weak(format!(
+                    "{} ago",
+                    format_remaining(notice.received.elapsed())
+                ));
+                ui.label(&notice.message);
+            });
+        }
+
+        ui.separator();
+
+        if ui.button("Clear").clicked() {
+            self.status.clear_notices();
+            ui.close_menu();
+        }
+    }
+}
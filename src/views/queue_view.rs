@@ -9,21 +9,38 @@ use crate::{
 pub struct QueueView<'a> {
     pub list_view: ListView<'a>,
     pub queue: &'a mut VecDeque<Request>,
+    pub active: Option<uuid::Uuid>,
     pub db: &'a db::Connection,
 }
 
 impl<'a> QueueView<'a> {
     pub fn display(self, ui: &mut egui::Ui) {
-        if let Action::Remove { index } = self.list_view.display(
+        match self.list_view.with_reordering().display(
             ui,
             "Nothing is queued",
             self.queue.is_empty(),
             |ui, add, req| {},
             self.queue.iter(),
         ) {
-            if let Some(item) = self.queue.remove(index) {
-                self.db.remove_from_queue(&item);
+            Action::Remove { index } => {
+                let Some(item) = self.queue.remove(index) else { return };
+                if let Err(err) = self.db.remove_from_queue(&item) {
+                    log::warn!("cannot remove {} from the queue: {err}", item.id);
+                }
             }
+            Action::Move { from, to } => {
+                let Some(item) = self.queue.remove(from) else { return };
+                let to = to.min(self.queue.len());
+                let id = item.id;
+                self.queue.insert(to, item);
+
+                // the active request is the first persisted entry
+                let offset = usize::from(self.active.is_some());
+                if let Err(err) = self.db.move_in_queue(id, to + offset) {
+                    log::warn!("cannot update the queue order: {err}");
+                }
+            }
+            Action::Add { .. } | Action::Nothing => {}
         }
     }
 }
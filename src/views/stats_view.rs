@@ -0,0 +1,230 @@
+use std::time::{Duration, Instant};
+
+use egui::{
+    plot::{Bar, BarChart, Plot},
+    ScrollArea, TextEdit,
+};
+
+use crate::{db, util::format_remaining};
+
+// these aggregate the whole history, so they're only reloaded every so often
+pub struct StatsSnapshot {
+    window: db::Window,
+    loaded: Instant,
+    top_requesters: Vec<db::Ranked>,
+    top_tracks: Vec<db::Ranked>,
+    top_artists: Vec<db::Ranked>,
+    per_day: Vec<(String, usize)>,
+    per_hour: [usize; 24],
+    listened: Duration,
+}
+
+impl StatsSnapshot {
+    const REFRESH: Duration = Duration::from_secs(10);
+    // how many entries each list shows
+    const TOP: usize = 10;
+
+    fn load(db: &db::Connection, window: db::Window) -> rusqlite::Result<Self> {
+        let range = window.range();
+        Ok(Self {
+            window,
+            loaded: Instant::now(),
+            top_requesters: db.top_requesters(&range, Self::TOP)?,
+            top_tracks: db.top_tracks(&range, Self::TOP)?,
+            top_artists: db.top_artists(&range, Self::TOP)?,
+            per_day: db.requests_per_day(&range)?,
+            per_hour: db.requests_per_hour(&range)?,
+            listened: db.listening_time(&range)?,
+        })
+    }
+
+    fn is_stale(&self, window: db::Window) -> bool {
+        self.window != window || self.loaded.elapsed() >= Self::REFRESH
+    }
+}
+
+// what was typed in for a custom window, kept even while it doesn't parse
+pub struct DateRange {
+    from: String,
+    to: String,
+}
+
+impl Default for DateRange {
+    fn default() -> Self {
+        let today = time::OffsetDateTime::now_utc().date();
+        Self {
+            from: (today - time::Duration::days(6)).to_string(),
+            to: today.to_string(),
+        }
+    }
+}
+
+impl DateRange {
+    fn window(&self) -> Option<db::Window> {
+        let (from, to) = (Self::parse(&self.from)?, Self::parse(&self.to)?);
+        (from <= to).then_some(db::Window::Custom { from, to })
+    }
+
+    // dates are `YYYY-MM-DD`, like the days in the chart
+    fn parse(date: &str) -> Option<time::Date> {
+        let mut parts = date.trim().splitn(3, '-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse::<u8>().ok()?.try_into().ok()?;
+        let day = parts.next()?.parse().ok()?;
+        time::Date::from_calendar_date(year, month, day).ok()
+    }
+}
+
+pub struct StatsView<'a> {
+    pub window: &'a mut db::Window,
+    pub dates: &'a mut DateRange,
+    pub snapshot: &'a mut Option<StatsSnapshot>,
+    pub db: &'a db::Connection,
+}
+
+impl<'a> StatsView<'a> {
+    pub fn display(self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            for window in db::Window::ALL {
+                ui.selectable_value(self.window, window, window.label());
+            }
+
+            let custom = matches!(self.window, db::Window::Custom { .. });
+            if ui.selectable_label(custom, "Custom").clicked() && !custom {
+                if self.dates.window().is_none() {
+                    *self.dates = DateRange::default();
+                }
+                *self.window = self.dates.window().unwrap_or_default();
+            }
+        });
+
+        if matches!(self.window, db::Window::Custom { .. }) {
+            ui.horizontal(|ui| {
+                for (label, date) in [("From", &mut self.dates.from), ("to", &mut self.dates.to)] {
+                    ui.label(label);
+                    ui.add(
+                        TextEdit::singleline(date)
+                            .hint_text("YYYY-MM-DD")
+                            .desired_width(90.0),
+                    );
+                }
+
+                // the last valid range stays up until this one is fixed
+                match self.dates.window() {
+                    Some(window) => *self.window = window,
+                    None => {
+                        let color = ui.visuals().error_fg_color;
+                        ui.colored_label(
+                            color,
+                            "Dates are YYYY-MM-DD, and the first can't be after the second",
+                        );
+                    }
+                }
+            });
+        }
+
+        if self
+            .snapshot
+            .as_ref()
+            .map_or(true, |snapshot| snapshot.is_stale(*self.window))
+        {
+            match StatsSnapshot::load(self.db, *self.window) {
+                Ok(snapshot) => {
+                    self.snapshot.replace(snapshot);
+                }
+                Err(err) => {
+                    log::warn!("cannot load the statistics: {err}");
+                    ui.label("Cannot load the statistics");
+                    return;
+                }
+            }
+        }
+
+        let Some(snapshot) = &*self.snapshot else { return };
+
+        ScrollArea::vertical().show(ui, |ui| {
+            ui.label(format!(
+                "{listened} listened {window}",
+                listened = format_remaining(snapshot.listened),
+                window = snapshot.window.describe()
+            ));
+
+            ui.separator();
+
+            ui.columns(3, |columns| {
+                Self::display_ranked(&mut columns[0], "Requesters", &snapshot.top_requesters);
+                Self::display_ranked(&mut columns[1], "Tracks", &snapshot.top_tracks);
+                Self::display_ranked(&mut columns[2], "Artists", &snapshot.top_artists);
+            });
+
+            ui.separator();
+
+            ui.strong("Requests per day (UTC)");
+            let days = snapshot
+                .per_day
+                .iter()
+                .map(|(day, _)| day.clone())
+                .collect::<Vec<_>>();
+            let bars = snapshot
+                .per_day
+                .iter()
+                .enumerate()
+                .map(|(i, (day, count))| Bar::new(i as f64, *count as f64).name(day))
+                .collect();
+            Self::display_chart(ui, "requests-per-day", bars, move |x| {
+                days.get(x as usize).cloned().unwrap_or_default()
+            });
+
+            ui.strong("Requests per hour (UTC)");
+            let bars = snapshot
+                .per_hour
+                .iter()
+                .enumerate()
+                .map(|(hour, count)| {
+                    Bar::new(hour as f64, *count as f64).name(format!("{hour:02}:00"))
+                })
+                .collect();
+            Self::display_chart(ui, "requests-per-hour", bars, |x| format!("{x:02}"));
+        });
+    }
+
+    fn display_ranked(ui: &mut egui::Ui, heading: &str, ranked: &[db::Ranked]) {
+        ui.strong(heading);
+        if ranked.is_empty() {
+            ui.weak("Nothing yet");
+        }
+
+        for (i, db::Ranked { name, count }) in ranked.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.monospace(format!("{:>2}", i + 1));
+                ui.label(name);
+                ui.weak(format!("({count})"));
+            });
+        }
+    }
+
+    // the x axis only has labels on whole numbers
+    fn display_chart(
+        ui: &mut egui::Ui,
+        id: &str,
+        bars: Vec<Bar>,
+        label: impl Fn(usize) -> String + 'static,
+    ) {
+        Plot::new(id)
+            .height(120.0)
+            .allow_zoom(false)
+            .allow_drag(false)
+            .allow_scroll(false)
+            .allow_boxed_zoom(false)
+            .allow_double_click_reset(false)
+            .include_y(0.0)
+            .show_x(false)
+            .x_axis_formatter(move |x, _| {
+                if x < 0.0 || x.fract() != 0.0 {
+                    return String::new();
+                }
+                label(x as usize)
+            })
+            .show(ui, |plot| plot.bar_chart(BarChart::new(bars)));
+    }
+}